@@ -1,10 +1,47 @@
 bitflags! {
-    pub struct Permissions: u8 {
-        const ReadOnly = 0b10000000;
-        const Owner    = 0b00010000;
-        const Streamer = 0b00001000;
-        const Mod      = 0b00000100;
-        const Sub      = 0b00000010;
-        const Viewer   = 0b00000001;
+    pub struct Permissions: u32 {
+        const ReadOnly = 0b0000_1000_0000;
+        const Owner    = 0b0000_0001_0000;
+        const Streamer = 0b0000_0000_1000;
+        const Mod      = 0b0000_0000_0100;
+        const Sub      = 0b0000_0000_0010;
+        const Viewer   = 0b0000_0000_0001;
+        // Past the first byte, so older aliases keep their values
+        const Vip      = 0b0001_0000_0000;
+        const Founder  = 0b0010_0000_0000;
+        const Artist   = 0b0100_0000_0000;
+        const Staff    = 0b1000_0000_0000;
+    }
+}
+
+// The letters `!alias` uses for each permission
+const LETTERS: &[(char, Permissions)] = &[
+    ('r', Permissions::ReadOnly),
+    ('o', Permissions::Owner),
+    ('b', Permissions::Streamer),
+    ('m', Permissions::Mod),
+    ('s', Permissions::Sub),
+    ('v', Permissions::Viewer),
+    ('i', Permissions::Vip),
+    ('f', Permissions::Founder),
+    ('a', Permissions::Artist),
+    ('t', Permissions::Staff),
+];
+
+impl Permissions {
+    pub fn from_letter(letter: char) -> Option<Permissions> {
+        LETTERS.iter().find(|(c, _)| *c == letter).map(|(_, p)| *p)
+    }
+
+    pub fn to_letters(&self) -> String {
+        LETTERS.iter().filter(|(_, p)| self.contains(*p)).map(|(c, _)| *c).collect()
+    }
+
+    pub fn from_letters(letters: &str) -> Option<Permissions> {
+        let mut auth = Permissions::empty();
+        for ch in letters.chars() {
+            auth |= Permissions::from_letter(ch)?;
+        }
+        Some(auth)
     }
 }
@@ -0,0 +1,84 @@
+use rand::{thread_rng, Rng};
+use std::time::Duration;
+
+// Exponential backoff between connection attempts. Each delay is picked at random from the upper
+// half of the current step so that channels that dropped together don't all reconnect together.
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, cap: Duration) -> Self {
+        Self {
+            base,
+            cap,
+            attempt: 0,
+        }
+    }
+
+    // Number of attempts since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self.base
+            .checked_mul(1 << self.attempt.min(16))
+            .map_or(self.cap, |step| step.min(self.cap));
+        self.attempt += 1;
+
+        let max = step.as_secs() * 1000 + u64::from(step.subsec_millis());
+        let millis = thread_rng().gen_range(max / 2, max + 1);
+        Duration::from_millis(millis)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each delay is in the upper half of its step
+    fn assert_within(delay: Duration, step: Duration) {
+        assert!(delay <= step, "{:?} is over {:?}", delay, step);
+        assert!(delay >= step / 2, "{:?} is under half of {:?}", delay, step);
+    }
+
+    #[test]
+    fn grows() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(300));
+        for attempt in 0..8 {
+            assert_eq!(backoff.attempt(), attempt);
+            assert_within(backoff.next_delay(), Duration::from_secs(1 << attempt));
+        }
+    }
+
+    #[test]
+    fn capped() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        for _ in 0..4 {
+            backoff.next_delay();
+        }
+        // Well past where the step would overflow
+        for _ in 0..100 {
+            assert_within(backoff.next_delay(), Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(60));
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_within(backoff.next_delay(), Duration::from_millis(500));
+        assert_within(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
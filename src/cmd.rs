@@ -1,45 +1,94 @@
 use auth::Permissions;
+use config::{Channel, CooldownNotice};
+use custom::{self, CustomCmd};
+use queue::Priority;
 use rand::distributions::Uniform;
 use rand::prelude::*;
 use regex::Regex;
-use rusqlite::Connection;
-use state::ThreadState;
-use std::collections::HashMap;
+use rusqlite::{Connection, Row};
+use state::{Status, ThreadState};
+use serde_json;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use supervisor::Event;
+use template::Template;
 use twitch::Context;
+use quotes;
+use roles;
+use users;
 use strawpoll;
 
+// Twitch drops anything longer
+const MAX_MSG_LEN: usize = 500;
+
+// How deep aliases may run other aliases, and how many commands one may come down to
+const MAX_ALIAS_DEPTH: usize = 8;
+const MAX_ALIAS_STEPS: usize = 16;
+
 pub struct CmdList {
     commands: HashMap<&'static str, Cmd>,
+    cooldowns: Cooldowns,
+    cooldown_notice: CooldownNotice,
 }
 
-impl CmdList {
-    pub fn new() -> Self {
-        let mut commands = HashMap::new();
+// Every built-in command, by name
+fn builtins() -> HashMap<&'static str, Cmd> {
+    let mut commands = HashMap::new();
+
+    commands.insert("alias", alias());
+    commands.insert("aliasmod", mod_alias());
+    commands.insert("aliases", aliases());
+    commands.insert("aliasinfo", aliasinfo());
+    commands.insert("role", role());
 
-        commands.insert("aliasmod", mod_alias());
+    commands.insert("cmd", cmd());
+    commands.insert("help", help());
+    commands.insert("commands", commands_cmd());
+    commands.insert("null", null());
+    commands.insert("quote", quote());
+    commands.insert("quoteadd", quoteadd());
+    commands.insert("quoteedit", quoteedit());
+    commands.insert("grab", grab());
+    commands.insert("quoterm", quoterm());
+    commands.insert("quoterestore", quoterestore());
+    commands.insert("quotepurge", quotepurge());
+    commands.insert("say", say());
+    commands.insert("thicc", thicc());
+    commands.insert("tiny", tinytext());
+    commands.insert("smol", smallcaps());
+    commands.insert("numberwang", numberwang());
+    commands.insert("8ball", eightball());
+    commands.insert("flipcoin", coinflip());
+    commands.insert("tcount", tcount());
+    commands.insert("roll", roll());
+    commands.insert("count", count());
+    commands.insert("version", version());
+    commands.insert("health", health());
+    commands.insert("reload", reload());
+    commands.insert("shutdown", shutdown());
 
-        commands.insert("null", null());
-        commands.insert("quote", quote());
-        commands.insert("quoteadd", quoteadd());
-        commands.insert("quoterm", quoterm());
-        commands.insert("say", say());
-        commands.insert("thicc", thicc());
-        commands.insert("tiny", tinytext());
-        commands.insert("smol", smallcaps());
-        commands.insert("numberwang", numberwang());
-        commands.insert("8ball", eightball());
-        commands.insert("flipcoin", coinflip());
-        commands.insert("tcount", tcount());
-        commands.insert("roll", roll());
-        commands.insert("count", count());
-        commands.insert("version", version());
-        commands.insert("shutdown", shutdown());
+    commands.insert("strawpoll", strawpoll_cmd());
 
-        commands.insert("strawpoll", strawpoll_cmd());
+    commands
+}
+
+// Whether `name` is taken by a built-in command
+pub fn is_builtin(name: &str) -> bool {
+    builtins().contains_key(name)
+}
 
-        Self { commands }
+impl CmdList {
+    pub fn new(chan_cfg: &Channel) -> Self {
+        Self {
+            commands: builtins(),
+            cooldowns: Cooldowns::new(),
+            cooldown_notice: chan_cfg.cooldown_notice,
+        }
+    }
+
+    pub fn configure(&mut self, chan_cfg: &Channel) {
+        self.cooldown_notice = chan_cfg.cooldown_notice;
     }
 
     pub fn exec(
@@ -47,10 +96,11 @@ impl CmdList {
         state: Arc<Mutex<ThreadState>>,
         context: &Context,
         command: &str,
-    ) -> Option<Vec<String>> {
+    ) -> Option<(Priority, Vec<String>)> {
         let (cmd, args) = pop_cmd(command);
         if cmd == "alias" {
-            if context.auth.intersects(Permissions::Streamer | Permissions::Mod) {
+            let allowed = may_run(state.lock().unwrap().db.as_ref(), context, "alias", &self.commands["alias"]);
+            if allowed {
                 if let Some(args) = args {
                     let (alias, command) = pop_cmd(&args);
                     let state = state.lock().unwrap();
@@ -65,32 +115,57 @@ impl CmdList {
                                     None => return None,
                                 };
                             }
-                            let (cmd, _) = pop_cmd(&command);
-                            if let Some(cmd) = self.commands.get(cmd.as_str()) {
+                            let prefix = state.cfg.cmd_prefix;
+                            // It would hide the custom command, as !cmd add won't hide an alias
+                            if custom::get(&db, &alias).is_ok() {
+                                let msg = format!("{}{} is already a command.", prefix, alias);
+                                return Some((Priority::Normal, vec![msg]));
+                            }
+                            let steps = alias_steps(&command);
+                            // Everyone who may use every command it runs
+                            let mut step_auth = None;
+                            for step in &steps {
+                                let (name, _) = split_step(step);
                                 // Make sure that the user who's aliasing has permission to use the
-                                // command being aliased
-                                if context.auth.intersects(cmd.auth) {
-                                    let mut auth = cmd.auth.clone();
-                                    auth.set(Permissions::ReadOnly, true);
-                                    let mut attr_val = true;
-                                    let mut attr;
-                                    for ch in auth_mod.chars() {
-                                        match ch {
-                                            '+' => { attr_val = true;  continue; },
-                                            '-' => { attr_val = false; continue; },
-                                            'r' => attr = Permissions::ReadOnly,
-                                            'o' => attr = Permissions::Owner,
-                                            'b' => attr = Permissions::Streamer,
-                                            'm' => attr = Permissions::Mod,
-                                            's' => attr = Permissions::Sub,
-                                            'v' => attr = Permissions::Viewer,
-                                            _ => continue,
-                                        }
-                                        auth.set(attr, attr_val);
+                                // commands being aliased
+                                let auth = if let Some(c) = self.commands.get(name) {
+                                    if !may_run(Some(&db), context, name, c) {
+                                        return None;
+                                    }
+                                    c.auth
+                                } else if let Some(target) = get_alias(&db, name) {
+                                    if !may_use(&db, context, &target) {
+                                        return None;
                                     }
-                                    rm_alias(&db, &alias);
-                                    add_alias(&db, &alias, &auth, &command);
+                                    if self.runs_alias(&db, name, &alias, 0) {
+                                        let msg = format!("{}{} would end up running itself.", prefix, alias);
+                                        return Some((Priority::Normal, vec![msg]));
+                                    }
+                                    target.auth
+                                } else {
+                                    let msg = format!("There is no {}{} command.", prefix, name);
+                                    return Some((Priority::Normal, vec![msg]));
+                                };
+                                step_auth = Some(step_auth.map_or(auth, |step_auth| step_auth & auth));
+                            }
+                            if let Some(mut auth) = step_auth {
+                                auth.set(Permissions::ReadOnly, true);
+                                let mut attr_val = true;
+                                let mut attr;
+                                for ch in auth_mod.chars() {
+                                    match ch {
+                                        '+' => { attr_val = true;  continue; },
+                                        '-' => { attr_val = false; continue; },
+                                        _ => match Permissions::from_letter(ch) {
+                                            Some(perm) => attr = perm,
+                                            None => continue,
+                                        },
+                                    }
+                                    auth.set(attr, attr_val);
                                 }
+                                let added_by = context.get_sender_display().unwrap_or_else(|| context.sender.clone());
+                                rm_alias(&db, &alias);
+                                add_alias(&db, &alias, &auth, &command, &added_by);
                             }
                         } else {
                             rm_alias(&db, &alias)
@@ -98,8 +173,8 @@ impl CmdList {
                     }
                     return None;
                 } else {
-                    let msgv = Some(vec![String::from("Usage: !alias <alias> [auth] <cmd> [args...]")]);
-                    return msgv;
+                    let msgv = vec![String::from("Usage: !alias <alias> [auth] <cmd> [args...]")];
+                    return Some((Priority::Normal, msgv));
                 }
             } else {
                 None
@@ -107,46 +182,480 @@ impl CmdList {
         } else {
             let mut msgv = None;
 
-            // Search for alias
+            // Search for alias, then custom command
             let mut alias_res = None;
+            let mut custom_res = None;
+            let prefix;
             {
                 let state = state.lock().unwrap();
+                prefix = state.cfg.cmd_prefix;
                 if let Some(db) = &state.db {
-                    alias_res = get_alias(&db, &cmd);
+                    alias_res = get_alias(&db, &cmd).map(|alias| {
+                        let mut expander = Expander::new(&self.commands, db, context, prefix);
+                        let expanded = expander.expand(&cmd, &alias, args.as_ref().map(String::as_str));
+                        (expander.chain, expanded)
+                    });
+                    if alias_res.is_none() {
+                        custom_res = custom::get(db, &cmd).ok();
+                    }
                 }
             }
 
             // Search for alias and exec
-            if let Some((alias_auth, alias_cmd)) = alias_res {
-                let (c, mut alias_args) = pop_cmd(&alias_cmd);
+            if let Some((chain, expanded)) = alias_res {
+                msgv = match expanded {
+                    // Refused without a word, as if it didn't exist
+                    Err(None) => None,
+                    expanded => {
+                        let (bucket, user_bucket) = (chain.bucket.as_ref(), chain.user_bucket.as_ref());
+                        match (self.cooldowns.take(&cmd, context, bucket, user_bucket), expanded) {
+                            (Ok(()), Ok(())) => self.run_chain(chain.steps, state, context),
+                            (Ok(()), Err(why)) => {
+                                why.map(|why| (Priority::Low, vec![format!("{}{} {}.", prefix, cmd, why)]))
+                            }
+                            (Err(wait), _) => self.cooldowns.notice(self.cooldown_notice, &cmd, context, wait)
+                                .map(|v| (Priority::Low, v)),
+                        }
+                    }
+                };
+            }
+            else if let Some(custom) = custom_res {
+                if context.auth.intersects(custom.auth) {
+                    let (bucket, user_bucket) = (Bucket::new(5, 30), Bucket::new(1, 15));
+                    msgv = match self.cooldowns.take(&cmd, context, Some(&bucket), Some(&user_bucket)) {
+                        Ok(()) => respond(&state, context, &cmd, &custom, args).map(|v| (Priority::Normal, v)),
+                        Err(wait) => self.cooldowns.notice(self.cooldown_notice, &cmd, context, wait)
+                            .map(|v| (Priority::Low, v)),
+                    };
+                }
+            }
+            // Else search for command and exec
+            else if let Some(c) = self.commands.get(&cmd.as_str()) {
+                if may_run(state.lock().unwrap().db.as_ref(), context, &cmd, c) {
+                    msgv = match self.cooldowns.take(&cmd, context, c.bucket.as_ref(), c.user_bucket.as_ref()) {
+                        Ok(()) => self.run(&cmd, c, state, &context, args),
+                        Err(wait) => self.cooldowns.notice(self.cooldown_notice, &cmd, context, wait)
+                            .map(|v| (Priority::Low, v)),
+                    };
+                }
+            }
+            msgv
+        }
+    }
+}
 
-                if !alias_auth.contains(Permissions::ReadOnly) {
-                    if let Some(args) = args {
-                        alias_args = if alias_args.is_some() {
-                            let mut tmp_args = alias_args.unwrap();
-                            tmp_args.push(' ');
-                            tmp_args.push_str(&args);
-                            Some(tmp_args)
-                        } else {
-                            Some(args)
-                        };
+impl CmdList {
+    // Run a built-in command. The few that need the command list itself are run here instead of
+    // by their `Cmd`.
+    fn run(
+        &self,
+        name: &str,
+        c: &Cmd,
+        state: Arc<Mutex<ThreadState>>,
+        context: &Context,
+        args: Option<String>,
+    ) -> Option<(Priority, Vec<String>)> {
+        let msgv = match name {
+            "cmd" => self.custom(&state, context, args),
+            "help" => self.help(&state, context, args),
+            "commands" => self.list(&state, context),
+            "role" if args.as_ref().map_or(false, |args| pop_cmd(args).0 == "cmd") => {
+                self.command_role(&state, context, args.and_then(|args| pop_cmd(&args).1))
+            }
+            _ => c.exec(state, context, args),
+        };
+        msgv.map(|v| (c.priority, v))
+    }
+
+    // Run the commands an alias came down to in order, replying with everything they said
+    fn run_chain(
+        &self,
+        steps: Vec<(String, Option<String>)>,
+        state: Arc<Mutex<ThreadState>>,
+        context: &Context,
+    ) -> Option<(Priority, Vec<String>)> {
+        let mut reply: Option<(Priority, Vec<String>)> = None;
+        for (name, args) in steps {
+            let c = match self.commands.get(name.as_str()) {
+                Some(c) => c,
+                None => continue,
+            };
+            if let Some((priority, msgv)) = self.run(&name, c, state.clone(), context, args) {
+                reply = match reply {
+                    Some((highest, mut all)) => {
+                        all.extend(msgv);
+                        Some((if priority > highest { priority } else { highest }, all))
+                    }
+                    None => Some((priority, msgv)),
+                };
+            }
+        }
+        reply
+    }
+
+    // Open a built-in command up to a role's members, or close it again. Only commands the caller's
+    // badges let them use can be given, so a role can't be used to hand out more than that.
+    fn command_role(&self, state: &Arc<Mutex<ThreadState>>, context: &Context, args: Option<String>) -> Option<Vec<String>> {
+        let state = state.lock().unwrap();
+        let db = state.db.as_ref()?;
+        let prefix = state.cfg.cmd_prefix;
+        let args = args.unwrap_or_default();
+        let mut args = args.split_whitespace();
+        let (name, role) = match (args.next(), args.next()) {
+            (Some(name), Some(role)) => (name.trim_start_matches(prefix), role.to_lowercase()),
+            _ => return Some(vec![format!("Usage: {}role cmd <command> <role>|none", prefix)]),
+        };
+        let msg = match self.commands.get(name) {
+            Some(c) if context.auth.intersects(c.auth) => {
+                if role == "none" {
+                    match roles::set_command_role(db, name, None) {
+                        Ok(()) => format!("{}{} is no longer open to a role.", prefix, name),
+                        Err(e) => format!("Unable to change {}{}: {}", prefix, name, e),
+                    }
+                } else if let Some(why) = bad_role(db, &role) {
+                    why
+                } else {
+                    match roles::set_command_role(db, name, Some(&role)) {
+                        Ok(()) => format!("Members of {} may use {}{} now.", role, prefix, name),
+                        Err(e) => format!("Unable to change {}{}: {}", prefix, name, e),
                     }
                 }
+            }
+            _ => format!("There is no {}{} command you can use.", prefix, name),
+        };
+        Some(vec![msg])
+    }
+
+    // Whether running alias `from` runs alias `to`, by way of however many others
+    fn runs_alias(&self, db: &Connection, from: &str, to: &str, depth: usize) -> bool {
+        if from == to {
+            return true;
+        }
+        if depth >= MAX_ALIAS_DEPTH {
+            return false;
+        }
+        get_alias(db, from).map_or(false, |alias| {
+            alias_steps(&alias.command).iter().any(|step| {
+                let (name, _) = split_step(step);
+                !self.commands.contains_key(name) && self.runs_alias(db, name, to, depth + 1)
+            })
+        })
+    }
+
+    // How to use a command, if the caller may use it. Asking about one they can't use is answered
+    // as if it didn't exist.
+    fn help(&self, state: &Arc<Mutex<ThreadState>>, context: &Context, args: Option<String>) -> Option<Vec<String>> {
+        let state = state.lock().unwrap();
+        let prefix = state.cfg.cmd_prefix;
+        let name = match args {
+            Some(args) => pop_cmd(args.trim_start_matches(prefix)).0,
+            None => {
+                let msg = format!("Usage: {}help <command>. {}commands lists the ones you can use.", prefix, prefix);
+                return Some(vec![msg]);
+            }
+        };
 
-                if let Some(c) = self.commands.get(c.as_str()) {
-                    if context.auth.intersects(alias_auth) {
-                        msgv = c.exec(state, &context, alias_args);
+        // An alias hides the command of the same name
+        let alias = state.db.as_ref().and_then(|db| get_alias(db, &name).map(|alias| (db, alias)));
+        let custom = state.db.as_ref().and_then(|db| custom::get(db, &name).ok());
+        let msg = match alias {
+            Some((db, alias)) => {
+                let steps = alias_steps(&alias.command);
+                let target = steps.first().map_or("null", |step| split_step(step).0);
+                let exists = self.commands.contains_key(target) || get_alias(db, target).is_some();
+                match self.commands.get(target) {
+                    _ if target == "null" || !exists || !may_use(db, context, &alias) => {
+                        format!("There is no {}{} command you can use.", prefix, name)
+                    }
+                    Some(c) if steps.len() == 1 => {
+                        format!("{}{} runs {}{}: {}", prefix, name, prefix, steps[0], c.description)
+                    }
+                    _ => {
+                        let runs: Vec<String> = steps.iter().map(|step| format!("{}{}", prefix, step)).collect();
+                        format!("{}{} runs {}", prefix, name, runs.join("; "))
                     }
                 }
             }
-            // Else search for command and exec
-            else if let Some(c) = self.commands.get(&cmd.as_str()) {
-                if context.auth.intersects(c.auth) {
-                    msgv = c.exec(state, &context, args);
+            // Then custom commands, which hide built-in ones
+            None => match (custom, self.commands.get(name.as_str())) {
+                (Some(custom), _) if context.auth.intersects(custom.auth) => {
+                    format!("{}{} is a custom command, made with {}cmd.", prefix, name, prefix)
                 }
+                (None, Some(c)) if may_run(state.db.as_ref(), context, &name, c) => {
+                    let mut msg = format!("{}{}: {}", prefix, c.usage, c.description);
+                    if let Some(example) = c.example {
+                        msg.push_str(&format!(" Example: {}{}", prefix, example));
+                    }
+                    msg
+                }
+                _ => format!("There is no {}{} command you can use.", prefix, name),
+            },
+        };
+        // A long enough chain of commands won't fit in one message
+        Some(fit_messages(vec![msg]))
+    }
+
+    // Every command, alias and custom command the caller may use
+    fn list(&self, state: &Arc<Mutex<ThreadState>>, context: &Context) -> Option<Vec<String>> {
+        let state = state.lock().unwrap();
+        let prefix = state.cfg.cmd_prefix;
+        let (aliases, customs) = match &state.db {
+            Some(db) => (all_aliases(db), custom::names(db).unwrap_or_default()),
+            None => (HashMap::new(), Vec::new()),
+        };
+
+        // Aliases hide custom commands, and both hide built-in ones
+        let mut names = BTreeSet::new();
+        for (name, c) in &self.commands {
+            let hidden = aliases.contains_key(*name) || customs.iter().any(|(custom, _)| custom == name);
+            if !hidden && may_run(state.db.as_ref(), context, name, c) {
+                names.insert(format!("{}{}", prefix, name));
+            }
+        }
+        for (name, auth) in &customs {
+            if !aliases.contains_key(name) && context.auth.intersects(*auth) {
+                names.insert(format!("{}{}", prefix, name));
+            }
+        }
+        if let Some(db) = &state.db {
+            for (name, alias) in &aliases {
+                // Aliases to null turn a command off
+                let steps = alias_steps(&alias.command);
+                let target = steps.first().map_or("null", |step| split_step(step).0);
+                let exists = self.commands.contains_key(target) || aliases.contains_key(target);
+                if target != "null" && exists && may_use(db, context, alias) {
+                    names.insert(format!("{}{}", prefix, name));
+                }
+            }
+        }
+
+        // Split over as many messages as it takes
+        let mut msgv = vec![String::from("Commands:")];
+        for name in names {
+            if msgv.last().unwrap().len() + name.len() + 2 > MAX_MSG_LEN {
+                msgv.push(String::new());
+            }
+            let msg = msgv.last_mut().unwrap();
+            if msg.ends_with(':') {
+                msg.push(' ');
+            } else if !msg.is_empty() {
+                msg.push_str(", ");
+            }
+            msg.push_str(&name);
+        }
+        Some(msgv)
+    }
+
+    // Add, change or remove a custom command. Names already used by a command or alias are taken.
+    fn custom(&self, state: &Arc<Mutex<ThreadState>>, context: &Context, args: Option<String>) -> Option<Vec<String>> {
+        let state = state.lock().unwrap();
+        let db = state.db.as_ref()?;
+        let prefix = state.cfg.cmd_prefix;
+        let usage = format!(
+            "Usage: {}cmd add <name> [+|-permissions...] <response> | edit <name> <response> | rm <name>",
+            prefix
+        );
+        let (sub, args) = pop_cmd(&args?);
+        let (name, response) = match args {
+            Some(args) => pop_cmd(&args),
+            None => return Some(vec![usage]),
+        };
+        let name = name.trim_start_matches(prefix).to_string();
+        if name.is_empty() {
+            return Some(vec![usage]);
+        }
+
+        let msg = match (sub.as_str(), response) {
+            ("add", Some(mut response)) => {
+                // Usable by everyone unless told otherwise, e.g. `+s -v`
+                let mut auth = Permissions::Viewer;
+                loop {
+                    let (word, rest) = pop_cmd(&response);
+                    match (auth_mods(auth, &word), rest) {
+                        (Some(new_auth), Some(rest)) => {
+                            auth = new_auth;
+                            response = rest;
+                        }
+                        _ => break,
+                    }
+                }
+                if self.commands.contains_key(name.as_str()) || get_alias(db, &name).is_some() {
+                    format!("{}{} is already a command.", prefix, name)
+                } else {
+                    let added_by = context.get_sender_display().unwrap_or_else(|| context.sender.clone());
+                    match custom::add(db, &name, &response, auth, &added_by) {
+                        Ok(true) => format!("Added {}{}.", prefix, name),
+                        Ok(false) => format!("{}{} already exists, {}cmd edit changes it.", prefix, name, prefix),
+                        Err(e) => format!("Unable to add {}{}: {}", prefix, name, e),
+                    }
+                }
+            }
+            ("edit", Some(response)) => match custom::edit(db, &name, &response) {
+                Ok(true) => format!("Changed {}{}.", prefix, name),
+                Ok(false) => format!("There is no custom command {}{}.", prefix, name),
+                Err(e) => format!("Unable to change {}{}: {}", prefix, name, e),
+            },
+            ("rm", None) => match custom::remove(db, &name) {
+                Ok(true) => format!("Removed {}{}.", prefix, name),
+                Ok(false) => format!("There is no custom command {}{}.", prefix, name),
+                Err(e) => format!("Unable to remove {}{}: {}", prefix, name, e),
+            },
+            _ => usage,
+        };
+        Some(vec![msg])
+    }
+}
+
+// A custom command's response, counting the use
+fn respond(
+    state: &Arc<Mutex<ThreadState>>,
+    context: &Context,
+    name: &str,
+    custom: &CustomCmd,
+    args: Option<String>,
+) -> Option<Vec<String>> {
+    let state = state.lock().unwrap();
+    let db = state.db.as_ref()?;
+    let count = custom::bump(db, name).unwrap_or(0);
+    let user = context.get_sender_display().unwrap_or_else(|| context.sender.clone());
+    let vars = custom::Vars {
+        user: &user,
+        args: args.as_ref().map(String::as_str),
+        channel: &state.cfg.name,
+        count,
+    };
+    let msg = custom::render(&custom.response, &vars);
+    if msg.trim().is_empty() {
+        None
+    } else {
+        Some(vec![msg])
+    }
+}
+
+// Permission changes like `+sv-r` applied to `auth`. None if it's not one, so that responses
+// starting with `+1` aren't taken for permissions.
+fn auth_mods(mut auth: Permissions, mods: &str) -> Option<Permissions> {
+    if !mods.starts_with('+') && !mods.starts_with('-') {
+        return None;
+    }
+    let mut set = true;
+    for ch in mods.chars() {
+        match ch {
+            '+' => set = true,
+            '-' => set = false,
+            _ => auth.set(Permissions::from_letter(ch)?, set),
+        }
+    }
+    Some(auth)
+}
+
+// The built-in commands an alias comes down to, and the cooldowns that cover them all
+#[derive(Default)]
+struct Chain {
+    steps: Vec<(String, Option<String>)>,
+    bucket: Option<Bucket>,
+    user_bucket: Option<Bucket>,
+}
+
+// Works out what an alias runs, through any aliases it runs in turn. Only names that aren't built-in
+// commands are taken for aliases, so that an alias can still wrap the command of the same name.
+// The caller only has to be allowed to use the alias they called: whoever made it had to be allowed
+// to use everything it runs, and its permissions are what open that up, e.g. `+v` on an alias of
+// `quoteadd`. That holds for the commands it runs and the aliases it runs alike.
+struct Expander<'a> {
+    commands: &'a HashMap<&'static str, Cmd>,
+    db: &'a Connection,
+    context: &'a Context,
+    prefix: char,
+    // The aliases being worked out, innermost last
+    stack: Vec<String>,
+    chain: Chain,
+}
+
+impl<'a> Expander<'a> {
+    fn new(commands: &'a HashMap<&'static str, Cmd>, db: &'a Connection, context: &'a Context, prefix: char) -> Self {
+        Self {
+            commands,
+            db,
+            context,
+            prefix,
+            stack: Vec::new(),
+            chain: Chain::default(),
+        }
+    }
+
+    // Add what `name` runs when called with `args` to the chain. Err(None) if the caller may not
+    // use it, or Err with why it can't be run. The cooldowns are worked out either way, so that
+    // being told why is rate limited too.
+    fn expand(&mut self, name: &str, alias: &Alias, args: Option<&str>) -> Result<(), Option<String>> {
+        if self.stack.is_empty() && !may_use(self.db, self.context, alias) {
+            return Err(None);
+        }
+        if self.stack.len() >= MAX_ALIAS_DEPTH {
+            return Err(Some(String::from("runs aliases too many levels deep")));
+        }
+
+        // Cooldowns set on the alias take precedence over those of the commands it runs
+        let steps = alias_steps(&alias.command);
+        self.chain.bucket = stricter(self.chain.bucket.take(), alias.bucket.as_ref());
+        self.chain.user_bucket = stricter(self.chain.user_bucket.take(), alias.user_bucket.as_ref());
+        for step in &steps {
+            if let Some(c) = self.commands.get(split_step(step).0) {
+                if alias.bucket.is_none() {
+                    self.chain.bucket = stricter(self.chain.bucket.take(), c.bucket.as_ref());
+                }
+                if alias.user_bucket.is_none() {
+                    self.chain.user_bucket = stricter(self.chain.user_bucket.take(), c.user_bucket.as_ref());
+                }
+            }
+        }
+
+        let argv: Vec<&str> = args.map_or_else(Vec::new, |args| args.split_whitespace().collect());
+        let templates: Vec<Option<Template>> = steps.iter().map(|step| split_step(step).1.map(Template::parse)).collect();
+        let has_args = templates.iter().flatten().any(Template::has_args);
+        let required = templates.iter().flatten().map(Template::required).max().unwrap_or(0);
+        alias.args.check(&argv, required).map_err(Some)?;
+
+        self.stack.push(name.to_string());
+        for (i, step) in steps.iter().enumerate() {
+            let cmd = split_step(step).0;
+
+            // Placeholders take the caller's arguments, otherwise they go on the end of the last
+            // command unless the alias is read only. Filling in also turns `$$` back into `$`.
+            let step_args = match &templates[i] {
+                Some(template) if has_args => Some(template.fill(&argv)),
+                template => {
+                    let step_args = template.as_ref().map(|template| template.fill(&[]));
+                    if i + 1 == steps.len() && !alias.auth.contains(Permissions::ReadOnly) {
+                        match (step_args, args) {
+                            (Some(step_args), Some(args)) => Some(format!("{} {}", step_args, args)),
+                            (step_args, args) => step_args.or_else(|| args.map(String::from)),
+                        }
+                    } else {
+                        step_args
+                    }
+                }
+            };
+            let step_args = step_args.filter(|args| !args.trim().is_empty());
+
+            if self.commands.contains_key(cmd) {
+                if self.chain.steps.len() >= MAX_ALIAS_STEPS {
+                    return Err(Some(String::from("runs too many commands")));
+                }
+                self.chain.steps.push((cmd.to_string(), step_args));
+            } else {
+                let prefix = self.prefix;
+                if self.stack.iter().any(|outer| outer == cmd) {
+                    return Err(Some(format!("runs {}{} again", prefix, cmd)));
+                }
+                let inner = get_alias(self.db, cmd)
+                    .ok_or_else(|| Some(format!("runs {}{}, which doesn't exist", prefix, cmd)))?;
+                self.expand(cmd, &inner, step_args.as_ref().map(String::as_str))
+                    .map_err(|why| why.map(|why| format!("runs {}{}, which {}", prefix, cmd, why)))?;
             }
-            msgv
         }
+        self.stack.pop();
+        Ok(())
     }
 }
 
@@ -156,7 +665,13 @@ pub struct Cmd {
              Option<String>)
              -> Option<Vec<String>>,
     pub bucket: Option<Bucket>,
+    pub user_bucket: Option<Bucket>,
+    pub priority: Priority,
     pub auth: Permissions,
+    // Shown by !help, without the command prefix
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub example: Option<&'static str>,
 }
 
 impl Cmd {
@@ -170,16 +685,258 @@ impl Cmd {
     }
 }
 
+// A token bucket holding `count` uses which refill evenly over `interval`. A count of zero
+// disables the bucket, which lets an alias lift the cooldown of the command it wraps.
+#[derive(Clone)]
 pub struct Bucket {
     pub count: u32,
     pub interval: Duration,
 }
 
+impl Bucket {
+    pub fn new(count: u32, secs: u64) -> Self {
+        Self {
+            count,
+            interval: Duration::from_secs(secs),
+        }
+    }
+
+    // Parse `<count>/<secs>`, or `off` for a disabled bucket
+    fn parse(s: &str) -> Option<Self> {
+        if s == "off" {
+            return Some(Self::new(0, 0));
+        }
+        let mut split = s.splitn(2, '/');
+        let count = split.next()?.parse::<u32>().ok()?;
+        let secs = split.next()?.parse::<u64>().ok()?;
+        if secs == 0 {
+            return None;
+        }
+        Some(Self::new(count, secs))
+    }
+
+    // Tokens regained per second
+    fn rate(&self) -> f64 {
+        f64::from(self.count) / as_secs(self.interval)
+    }
+}
+
+// The bucket is kept with its level, so the level can be brought up to date without the command
+struct Tokens {
+    level: f64,
+    last: Instant,
+    bucket: Bucket,
+}
+
+impl Tokens {
+    fn full(bucket: &Bucket) -> Self {
+        Self {
+            level: f64::from(bucket.count),
+            last: Instant::now(),
+            bucket: bucket.clone(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.level += as_secs(now - self.last) * self.bucket.rate();
+        if self.level > f64::from(self.bucket.count) {
+            self.level = f64::from(self.bucket.count);
+        }
+        self.last = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.level >= f64::from(self.bucket.count)
+    }
+
+    // Time until a token is available, if there isn't one already
+    fn wait(&self) -> Option<Duration> {
+        if self.level >= 1.0 {
+            None
+        } else {
+            let secs = (1.0 - self.level) / self.bucket.rate();
+            Some(Duration::from_millis((secs * 1000.0).ceil() as u64))
+        }
+    }
+}
+
+// Token state for every command or alias that has been invoked, keyed by the name it was invoked
+// with so that aliases don't share a cooldown with the command they wrap.
+struct Cooldowns {
+    global: HashMap<String, Tokens>,
+    user: HashMap<(String, String), Tokens>,
+    notified: HashMap<(String, String), Instant>,
+}
+
+impl Cooldowns {
+    fn new() -> Self {
+        Self {
+            global: HashMap::new(),
+            user: HashMap::new(),
+            notified: HashMap::new(),
+        }
+    }
+
+    // Take a token from both the global and the per-user bucket, or neither if either is empty.
+    // Streamers and mods are never limited.
+    fn take(
+        &mut self,
+        name: &str,
+        context: &Context,
+        bucket: Option<&Bucket>,
+        user_bucket: Option<&Bucket>,
+    ) -> Result<(), Duration> {
+        if context.auth.intersects(Permissions::Owner | Permissions::Streamer | Permissions::Mod) {
+            return Ok(());
+        }
+        let bucket = bucket.filter(|b| b.count > 0);
+        let user_bucket = user_bucket.filter(|b| b.count > 0);
+
+        // Forget users whose buckets have filled back up so the maps don't grow forever
+        if self.user.len() > 512 {
+            for tokens in self.user.values_mut() {
+                tokens.refill();
+            }
+            self.user.retain(|_, tokens| !tokens.is_full());
+        }
+        if self.notified.len() > 512 {
+            let now = Instant::now();
+            self.notified.retain(|_, until| *until > now);
+        }
+
+        let mut wait = None;
+        if let Some(bucket) = bucket {
+            let tokens = self.global.entry(name.to_string()).or_insert_with(|| Tokens::full(bucket));
+            tokens.bucket = bucket.clone();
+            tokens.refill();
+            wait = tokens.wait();
+        }
+        if let Some(user_bucket) = user_bucket {
+            let key = (name.to_string(), context.user_key().to_string());
+            let tokens = self.user.entry(key).or_insert_with(|| Tokens::full(user_bucket));
+            tokens.bucket = user_bucket.clone();
+            tokens.refill();
+            if let Some(user_wait) = tokens.wait() {
+                if wait.map_or(true, |wait| user_wait > wait) {
+                    wait = Some(user_wait);
+                }
+            }
+        }
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+
+        if bucket.is_some() {
+            if let Some(tokens) = self.global.get_mut(name) {
+                tokens.level -= 1.0;
+            }
+        }
+        if user_bucket.is_some() {
+            let key = (name.to_string(), context.user_key().to_string());
+            if let Some(tokens) = self.user.get_mut(&key) {
+                tokens.level -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    // Build the message telling a user that `name` is on cooldown. Each user is only told once
+    // per cooldown, otherwise spamming the command would just spam the notice instead.
+    fn notice(
+        &mut self,
+        notice: CooldownNotice,
+        name: &str,
+        context: &Context,
+        wait: Duration,
+    ) -> Option<Vec<String>> {
+        if notice == CooldownNotice::None {
+            return None;
+        }
+        let now = Instant::now();
+        let key = (name.to_string(), context.user_key().to_string());
+        if let Some(until) = self.notified.get(&key) {
+            if *until > now {
+                return None;
+            }
+        }
+        self.notified.insert(key, now + wait);
+
+        let secs = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+        match notice {
+            CooldownNotice::Reply => {
+                let display = context.get_sender_display().unwrap_or_else(|| context.sender.clone());
+                Some(vec![format!("@{}, {} is on cooldown for {}s.", display, name, secs)])
+            }
+            CooldownNotice::Whisper => {
+                Some(vec![format!("/w {} {} is on cooldown for {}s.", context.sender, name, secs)])
+            }
+            CooldownNotice::None => None,
+        }
+    }
+}
+
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 //                                          Bot Commands                                          //
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
+// `alias`, `cmd`, `help`, `commands` and `role cmd` need the command list, so `CmdList` runs them
+// itself. Their entries are here for their permissions and help.
+
+fn alias() -> Cmd {
+    Cmd {
+        func: |_, _, _| None,
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
+        auth: Permissions::Streamer | Permissions::Mod,
+        usage: "alias <alias> [+|-permissions...] [<command> [args...][; <command> [args...]]...]",
+        description: "Makes a command that runs others, or aliases, in turn. Removes one if no command is given.",
+        example: Some("alias discord +v say discord.gg/XXXXXXX"),
+    }
+}
+
+fn cmd() -> Cmd {
+    Cmd {
+        func: |_, _, _| None,
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
+        auth: Permissions::Streamer | Permissions::Mod,
+        usage: "cmd add <name> [+|-permissions...] <response> | edit <name> <response> | rm <name>",
+        description: "Manages custom commands. Responses can use $(user), $(touser), $(args), $(1), \
+                      $(channel), $(count), $(random a|b|c) and $(time).",
+        example: Some("cmd add hug $(user) hugs $(touser)!"),
+    }
+}
+
+fn help() -> Cmd {
+    Cmd {
+        func: |_, _, _| None,
+        bucket: Some(Bucket::new(5, 30)),
+        user_bucket: Some(Bucket::new(1, 15)),
+        priority: Priority::Low,
+        auth: Permissions::Viewer,
+        usage: "help <command>",
+        description: "Shows how to use a command.",
+        example: Some("help roll"),
+    }
+}
+
+fn commands_cmd() -> Cmd {
+    Cmd {
+        func: |_, _, _| None,
+        bucket: Some(Bucket::new(3, 30)),
+        user_bucket: Some(Bucket::new(1, 60)),
+        priority: Priority::Low,
+        auth: Permissions::Viewer,
+        usage: "commands",
+        description: "Lists the commands you can use.",
+        example: None,
+    }
+}
+
 fn mod_alias() -> Cmd {
     Cmd {
         func: |t_state, _, args| {
@@ -188,27 +945,82 @@ fn mod_alias() -> Cmd {
                 if let Some(db) = &t_state.db {
                     let (alias, args) = pop_cmd(&args);
                     if let Some(args) = args {
-                        if let Some((auth, _)) = get_alias(&db, &alias) {
-                            let mut auth = auth;
-                            let mut attr_val = true;
-                            let mut attr;
-                            for ch in args.chars() {
-                                match ch {
-                                    '+' => { attr_val = true;  continue; },
-                                    '-' => { attr_val = false; continue; },
-                                    'r' => attr = Permissions::ReadOnly,
-                                    'o' => attr = Permissions::Owner,
-                                    'b' => attr = Permissions::Streamer,
-                                    'm' => attr = Permissions::Mod,
-                                    's' => attr = Permissions::Sub,
-                                    'v' => attr = Permissions::Viewer,
-                                    _ => continue,
+                        if let Some(alias_row) = get_alias(&db, &alias) {
+                            let mut auth = alias_row.auth;
+                            let mut bucket = alias_row.bucket;
+                            let mut user_bucket = alias_row.user_bucket;
+                            let mut role = alias_row.role;
+                            let mut rules = alias_row.args;
+                            for arg in args.split_whitespace() {
+                                // How many arguments it takes, e.g. `args=2` or `args=1-`
+                                if arg.starts_with("args=") {
+                                    let _ = rules.parse_count(&arg["args=".len()..]);
+                                    continue;
+                                }
+                                // What an argument has to match, e.g. `arg1=\d+` or `arg1=none`
+                                let pos = arg.find('=').unwrap_or(0);
+                                if arg.starts_with("arg") && pos > 3 {
+                                    if let Ok(index) = arg[3..pos].parse::<usize>() {
+                                        let pattern = &arg[pos + 1..];
+                                        if pattern == "none" {
+                                            rules.patterns.remove(&index);
+                                        } else if index > 0 && Regex::new(pattern).is_ok() {
+                                            rules.patterns.insert(index, pattern.to_string());
+                                        }
+                                        continue;
+                                    }
+                                }
+                                // The role whose members may also use it, e.g. `role=editors`. Nothing
+                                // is changed if it isn't one.
+                                if arg.starts_with("role=") {
+                                    let name = arg["role=".len()..].to_lowercase();
+                                    if name == "none" {
+                                        role = None;
+                                    } else if let Some(why) = bad_role(&db, &name) {
+                                        return Some(vec![why]);
+                                    } else {
+                                        role = Some(name);
+                                    }
+                                    continue;
+                                }
+                                // Cooldown overrides, e.g. `cooldown=3/30` or `usercooldown=off`
+                                if let Some(pos) = arg.find('=') {
+                                    let (key, val) = (&arg[..pos], &arg[pos + 1..]);
+                                    let val = if val == "default" {
+                                        None
+                                    } else if let Some(b) = Bucket::parse(val) {
+                                        Some(b)
+                                    } else {
+                                        continue;
+                                    };
+                                    match key {
+                                        "cooldown" => bucket = val,
+                                        "usercooldown" => user_bucket = val,
+                                        _ => {}
+                                    }
+                                    continue;
+                                }
+
+                                let mut attr_val = true;
+                                let mut attr;
+                                for ch in arg.chars() {
+                                    match ch {
+                                        '+' => { attr_val = true;  continue; },
+                                        '-' => { attr_val = false; continue; },
+                                        _ => match Permissions::from_letter(ch) {
+                                            Some(perm) => attr = perm,
+                                            None => continue,
+                                        },
+                                    }
+                                    auth.set(attr, attr_val);
                                 }
-                                auth.set(attr, attr_val);
                             }
-                            let auth: u8 = auth.bits();
+                            let auth: u32 = auth.bits();
                             db.execute("UPDATE alias SET auth=(?1) WHERE alias=?2", &[&auth, &alias])
                                 .unwrap();
+                            set_alias_buckets(&db, &alias, bucket.as_ref(), user_bucket.as_ref());
+                            let _ = db.execute("UPDATE alias SET role=?1 WHERE alias=?2", &[&role, &alias]);
+                            set_alias_args(&db, &alias, &rules);
                         }
                     }
                 }
@@ -216,7 +1028,120 @@ fn mod_alias() -> Cmd {
             None
         },
         bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
+        auth: Permissions::Streamer | Permissions::Mod,
+        usage: "aliasmod <alias> [+|-permissions...] [cooldown=<n>/<secs>] [usercooldown=<n>/<secs>] [role=<role>] \
+                [args=<min>[-[max]]] [arg<n>=<regex>]",
+        description: "Changes who may use an alias, its cooldowns, and what arguments it takes.",
+        example: Some("aliasmod quoteadd +s"),
+    }
+}
+
+// Every alias's name, over as many pages as it takes
+fn aliases() -> Cmd {
+    Cmd {
+        func: |t_state, _, args| {
+            let t_state = t_state.lock().unwrap();
+            let db = t_state.db.as_ref()?;
+            let prefix = t_state.cfg.cmd_prefix;
+            let mut names: Vec<String> = all_aliases(db).into_iter().map(|(name, _)| name).collect();
+            names.sort();
+
+            // Leave room for the page numbers
+            let mut pages: Vec<String> = Vec::new();
+            for name in names {
+                let name = format!("{}{}", prefix, name);
+                match pages.last_mut() {
+                    Some(page) if page.len() + name.len() + 2 <= MAX_MSG_LEN - 50 => {
+                        page.push_str(", ");
+                        page.push_str(&name);
+                    }
+                    _ => pages.push(name),
+                }
+            }
+
+            let page = match args {
+                Some(args) => args.trim().parse::<usize>().ok()?,
+                None => 1,
+            };
+            let msg = if pages.is_empty() {
+                String::from("There are no aliases.")
+            } else if page == 0 || page > pages.len() {
+                let plural = if pages.len() == 1 { "page" } else { "pages" };
+                format!("There are only {} {} of aliases.", pages.len(), plural)
+            } else if pages.len() == 1 {
+                format!("Aliases: {}", pages[0])
+            } else if page < pages.len() {
+                let more = format!("{}aliases {} for more", prefix, page + 1);
+                format!("Aliases ({}/{}, {}): {}", page, pages.len(), more, pages[page - 1])
+            } else {
+                format!("Aliases ({}/{}): {}", page, pages.len(), pages[page - 1])
+            };
+            Some(vec![msg])
+        },
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Low,
         auth: Permissions::Streamer | Permissions::Mod,
+        usage: "aliases [page]",
+        description: "Lists every alias.",
+        example: Some("aliases 2"),
+    }
+}
+
+// What an alias runs, who may use it, and who made it
+fn aliasinfo() -> Cmd {
+    Cmd {
+        func: |t_state, _, args| {
+            let t_state = t_state.lock().unwrap();
+            let db = t_state.db.as_ref()?;
+            let prefix = t_state.cfg.cmd_prefix;
+            let name = pop_cmd(args?.trim_start_matches(prefix)).0;
+            let alias = match get_alias(db, &name) {
+                Some(alias) => alias,
+                None => return Some(vec![format!("There is no alias {}{}.", prefix, name)]),
+            };
+
+            let runs: Vec<String> = alias_steps(&alias.command)
+                .iter()
+                .map(|step| format!("{}{}", prefix, step))
+                .collect();
+            let mut parts = vec![format!("{}{} runs {}.", prefix, name, runs.join("; "))];
+            if alias.auth.is_empty() {
+                parts.push(String::from("Permissions: none."));
+            } else {
+                parts.push(format!("Permissions: +{} ({:?}).", alias.auth.to_letters(), alias.auth));
+            }
+            if let Some(role) = &alias.role {
+                parts.push(format!("Role: {}.", role));
+            }
+            let cooldown = |bucket: &Bucket| match bucket.count {
+                0 => String::from("off"),
+                count => format!("{}/{}s", count, bucket.interval.as_secs()),
+            };
+            if let Some(bucket) = &alias.bucket {
+                parts.push(format!("Cooldown: {}.", cooldown(bucket)));
+            }
+            if let Some(bucket) = &alias.user_bucket {
+                parts.push(format!("User cooldown: {}.", cooldown(bucket)));
+            }
+            match (&alias.added_by, &alias.added_on) {
+                (Some(by), Some(on)) => parts.push(format!("Added by {} on {}.", by, on)),
+                (Some(by), None) => parts.push(format!("Added by {}.", by)),
+                (None, Some(on)) => parts.push(format!("Added on {}.", on)),
+                (None, None) => {}
+            }
+
+            Some(fit_messages(parts))
+        },
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Low,
+        auth: Permissions::Streamer | Permissions::Mod,
+        usage: "aliasinfo <alias>",
+        description: "Shows what an alias runs, who may use it and who added it.",
+        example: Some("aliasinfo discord"),
     }
 }
 
@@ -224,7 +1149,12 @@ fn null() -> Cmd {
     Cmd {
         func: |_, _, _| None,
         bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
         auth: Permissions::Streamer,
+        usage: "null",
+        description: "Does nothing. Alias a command to it to turn that command off.",
+        example: Some("alias tcount null"),
     }
 }
 
@@ -236,7 +1166,12 @@ fn say() -> Cmd {
             None
         },
         bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
         auth: Permissions::Streamer | Permissions::Mod,
+        usage: "say <message>",
+        description: "Sends a message to the chat.",
+        example: Some("say Hello, world!"),
     }
 }
 
@@ -257,7 +1192,12 @@ fn count() -> Cmd {
             None
         },
         bucket: None,
+        user_bucket: None,
+        priority: Priority::Low,
         auth: Permissions::Owner,
+        usage: "count <n>",
+        description: "Sends the numbers up to n, one message each.",
+        example: Some("count 5"),
     }
 }
 
@@ -307,7 +1247,12 @@ fn strawpoll_cmd() -> Cmd {
             None
         },
         bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
         auth: Permissions::Streamer | Permissions::Mod,
+        usage: "strawpoll [<title> | <option> | <option>...]",
+        description: "Makes a strawpoll, or shows the results of the last one.",
+        example: Some("strawpoll Is Zed human? | Yes | Yes"),
     }
 }
 
@@ -376,8 +1321,13 @@ fn thicc() -> Cmd {
         } else {
             None
         },
-        bucket: None,
+        bucket: Some(Bucket::new(5, 30)),
+        user_bucket: Some(Bucket::new(1, 15)),
+        priority: Priority::Low,
         auth: Permissions::Viewer,
+        usage: "thicc <text>",
+        description: "Writes text in the thicc alphabet.",
+        example: Some("thicc Hello, world!"),
     }
 }
 
@@ -446,8 +1396,13 @@ fn tinytext() -> Cmd {
         } else {
             None
         },
-        bucket: None,
+        bucket: Some(Bucket::new(5, 30)),
+        user_bucket: Some(Bucket::new(1, 15)),
+        priority: Priority::Low,
         auth: Permissions::Viewer,
+        usage: "tiny <text>",
+        description: "Writes text in tiny letters.",
+        example: Some("tiny Hello, world!"),
     }
 }
 
@@ -516,8 +1471,13 @@ fn smallcaps() -> Cmd {
         } else {
             None
         },
-        bucket: None,
+        bucket: Some(Bucket::new(5, 30)),
+        user_bucket: Some(Bucket::new(1, 15)),
+        priority: Priority::Low,
         auth: Permissions::Viewer,
+        usage: "smol <text>",
+        description: "Writes text in small caps.",
+        example: Some("smol Hello, world!"),
     }
 }
 
@@ -549,8 +1509,13 @@ fn numberwang() -> Cmd {
             }
             None
         },
-        bucket: None,
+        bucket: Some(Bucket::new(5, 30)),
+        user_bucket: Some(Bucket::new(1, 15)),
+        priority: Priority::Low,
         auth: Permissions::Viewer,
+        usage: "numberwang <number>",
+        description: "Says whether a number is numberwang.",
+        example: Some("numberwang 420.69"),
     }
 }
 
@@ -577,8 +1542,13 @@ fn eightball() -> Cmd {
             ];
             Some(vec![String::from(answers[rng.gen_range(0, answers.len())])])
         },
-        bucket: None,
+        bucket: Some(Bucket::new(5, 30)),
+        user_bucket: Some(Bucket::new(1, 15)),
+        priority: Priority::Low,
         auth: Permissions::Viewer,
+        usage: "8ball [question]",
+        description: "Asks the all knowing 8ball a question.",
+        example: Some("8ball should I sub to ZedExV?"),
     }
 }
 
@@ -604,8 +1574,13 @@ fn coinflip() -> Cmd {
         } else {
             return Some(vec![String::from("Tails")]);
         },
-        bucket: None,
+        bucket: Some(Bucket::new(5, 30)),
+        user_bucket: Some(Bucket::new(1, 15)),
+        priority: Priority::Low,
         auth: Permissions::Viewer,
+        usage: "flipcoin [count]",
+        description: "Flips a coin, or several.",
+        example: Some("flipcoin 7"),
     }
 }
 
@@ -668,8 +1643,13 @@ fn roll() -> Cmd {
                 Some(vec![roll_string])
             }
         },
-        bucket: None,
+        bucket: Some(Bucket::new(5, 30)),
+        user_bucket: Some(Bucket::new(1, 15)),
+        priority: Priority::Low,
         auth: Permissions::Viewer,
+        usage: "roll [[X]dY + ... [+|- Z]]",
+        description: "Rolls dice, a d20 if none are given.",
+        example: Some("roll 2d20 + d7 - 4"),
     }
 }
 
@@ -692,89 +1672,201 @@ fn tcount() -> Cmd {
             let tcount = (hash % 101) as u8;
             Some(vec![format!("{}: {}/100", display, tcount)])
         },
-        bucket: None,
+        bucket: Some(Bucket::new(5, 30)),
+        user_bucket: Some(Bucket::new(1, 60)),
+        priority: Priority::Low,
         auth: Permissions::Viewer,
+        usage: "tcount",
+        description: "Tells you your T-count, out of 100.",
+        example: None,
     }
 }
 
 fn quote() -> Cmd {
     Cmd {
         func: |t_state, _, args| {
-            let t_state = t_state.lock().unwrap();
+            let mut t_state = t_state.lock().unwrap();
+            let t_state = &mut *t_state;
             if let Some(db) = &t_state.db {
-                let mut quote;
+                let template = &t_state.cfg.quote_format;
+                let quote;
                 if let Some(args) = args {
+                    let (sub, rest) = pop_cmd(&args);
+                    let rest = rest.filter(|rest| !rest.is_empty());
+                    match sub.as_str() {
+                        "search" | "by" => {
+                            let filter = quote_filter(&sub, rest)?;
+                            let total = quotes::count(db, &filter).ok()?;
+                            let search = quotes::Search { filter, offset: 0, total };
+                            let msg = quote_page(db, &search, template, t_state.cfg.cmd_prefix);
+                            t_state.quote_search = Some(search);
+                            return msg;
+                        }
+                        "next" => {
+                            let mut search = t_state.quote_search.take()?;
+                            search.offset += 1;
+                            if search.offset >= search.total {
+                                return Some(vec![String::from("No more matching quotes.")]);
+                            }
+                            let msg = quote_page(db, &search, template, t_state.cfg.cmd_prefix);
+                            t_state.quote_search = Some(search);
+                            return msg;
+                        }
+                        "count" => {
+                            let filter = match rest {
+                                Some(rest) => {
+                                    let sub = if rest.starts_with("by ") { "by" } else { "search" };
+                                    let rest = if sub == "by" { rest[3..].to_string() } else { rest };
+                                    quote_filter(sub, Some(rest))?
+                                }
+                                None => quotes::Filter::default(),
+                            };
+                            let total = quotes::count(db, &filter).ok()?;
+                            let msg = match total {
+                                1 => String::from("1 quote matches."),
+                                n => format!("{} quotes match.", n),
+                            };
+                            return Some(vec![msg]);
+                        }
+                        _ => {}
+                    }
+
                     if let Ok(i) = args.parse::<u32>() {
-                        quote = db.query_row(
-                            "SELECT * FROM quote WHERE id=?1;",
-                            &[&i],
-                            |row| {
-                                let id: u32 = row.get(0);
-                                let q: String = row.get(1);
-                                format!("[{}] {}", id, q)
-                            },
-                            );
+                        quote = quotes::get(db, i);
                     } else {
                         return None
                     }
                 } else {
-                    quote = db.query_row(
-                        "SELECT * FROM quote ORDER BY RANDOM() LIMIT 1;",
-                        &[],
-                        |row| {
-                            let id: u32 = row.get(0);
-                            let q: String = row.get(1);
-                            format!("[{}] {}", id, q)
-                        },
-                        );
+                    quote = quotes::random(db);
                 }
                 if let Ok(quote) = quote {
-                    let mut v = Vec::new();
-                    v.push(quote);
-                    return Some(v);
+                    return Some(vec![quote.format(template)]);
                 }
             }
             None
         },
-        bucket: None,
+        bucket: Some(Bucket::new(3, 30)),
+        user_bucket: Some(Bucket::new(1, 30)),
+        priority: Priority::Normal,
         auth: Permissions::Viewer,
+        usage: "quote [id | search <terms> | by <person> [terms] | next | count [by <person>] [terms]]",
+        description: "Shows a quote by id or at random, or searches them.",
+        example: Some("quote search cake lie"),
     }
 }
 
 fn quoteadd() -> Cmd {
     Cmd {
-        func: |t_state, _, args| {
+        func: |t_state, context, args| {
             if let Some(args) = args {
+                // <quote> [| author [| category]]. The spaces are needed, so a `|` in the quote
+                // itself doesn't split it.
+                let mut fields = args.splitn(3, " | ").map(str::trim);
+                let text = fields.next().unwrap_or("");
+                let author = fields.next().filter(|s| !s.is_empty());
+                let category = fields.next().filter(|s| !s.is_empty());
+                if text.is_empty() {
+                    return None;
+                }
+
+                let added_by = context.get_sender_display().unwrap_or_else(|| context.sender.clone());
                 let t_state = t_state.lock().unwrap();
                 if let Some(db) = &t_state.db {
-                    db.execute("INSERT INTO quote (quote) values (?1)", &[&args])
-                        .unwrap();
-                    let msg = db.query_row("SELECT * FROM quote ORDER BY id DESC LIMIT 1;", &[], |row| {
-                        let id: u32 = row.get(0);
-                        format!("Quote #{} added.", id)
-                    });
-                    if let Ok(msg) = msg {
-                        return Some(vec![msg]);
+                    if let Ok(id) = quotes::add(db, text, author, category, &added_by, None) {
+                        return Some(vec![format!("Quote #{} added.", id)]);
                     }
                 }
             }
             None
         },
         bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
         auth: Permissions::Streamer | Permissions::Mod,
+        usage: "quoteadd <quote> [| <author> [| <category>]]",
+        description: "Adds a quote.",
+        example: Some("quoteadd Hello, world! | ZedExV | Dark Souls"),
     }
 }
 
-fn quoterm() -> Cmd {
+// Quote something said in chat recently, by default the user's last message
+fn grab() -> Cmd {
+    Cmd {
+        func: |t_state, context, args| {
+            let (user, n) = pop_cmd(&args?);
+            let user = user.trim_start_matches('@');
+            let n = match n {
+                Some(n) => n.parse::<usize>().ok()?,
+                None => 1,
+            };
+
+            let added_by = context.get_sender_display().unwrap_or_else(|| context.sender.clone());
+            let t_state = t_state.lock().unwrap();
+            let line = match t_state.recent.by_user(user, n) {
+                Some(line) => line,
+                None => return Some(vec![format!("I haven't seen that from {} recently.", user)]),
+            };
+            if let Some(db) = &t_state.db {
+                let author = Some(line.display_name.as_str());
+                if let Ok(id) = quotes::add(db, &line.text, author, None, &added_by, Some(line.time)) {
+                    return Some(vec![format!("Quote #{} added.", id)]);
+                }
+            }
+            None
+        },
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
+        auth: Permissions::Streamer | Permissions::Mod,
+        usage: "grab <user> [n]",
+        description: "Quotes something a user said recently, their last message unless n goes further back.",
+        example: Some("grab ZedExV"),
+    }
+}
+
+fn quoteedit() -> Cmd {
     Cmd {
         func: |t_state, _, args| {
+            if let (id, Some(text)) = pop_cmd(&args?) {
+                let id = id.parse::<u32>().ok()?;
+                let t_state = t_state.lock().unwrap();
+                if let Some(db) = &t_state.db {
+                    let msg = match quotes::edit(db, id, &text) {
+                        Ok(true) => format!("Quote #{} updated.", id),
+                        _ => format!("There is no quote #{}.", id),
+                    };
+                    return Some(vec![msg]);
+                }
+            }
+            None
+        },
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
+        auth: Permissions::Streamer | Permissions::Mod,
+        usage: "quoteedit <id> <quote>",
+        description: "Replaces the text of a quote.",
+        example: Some("quoteedit 3 Hello, world!"),
+    }
+}
+
+fn quoterm() -> Cmd {
+    Cmd {
+        func: |t_state, context, args| {
             if let Some(args) = args {
                 if let Ok(i) = args.parse::<u32>() {
                     if i > 0 {
+                        let deleted_by = context.get_sender_display().unwrap_or_else(|| context.sender.clone());
                         let t_state = t_state.lock().unwrap();
                         if let Some(db) = &t_state.db {
-                            let id = format!("{}", i);
-                            db.execute("DELETE FROM quote WHERE id=?1", &[&id]).unwrap();
+                            let msg = match quotes::delete(db, i, &deleted_by) {
+                                Ok(true) => format!(
+                                    "Quote #{} deleted, {}quoterestore {} brings it back.",
+                                    i, t_state.cfg.cmd_prefix, i
+                                ),
+                                _ => format!("There is no quote #{}.", i),
+                            };
+                            return Some(vec![msg]);
                         }
                     }
                 }
@@ -782,7 +1874,132 @@ fn quoterm() -> Cmd {
             None
         },
         bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
+        auth: Permissions::Streamer | Permissions::Mod,
+        usage: "quoterm <id>",
+        description: "Removes a quote. It can be restored.",
+        example: Some("quoterm 3"),
+    }
+}
+
+fn quoterestore() -> Cmd {
+    Cmd {
+        func: |t_state, _, args| {
+            let id = args?.parse::<u32>().ok()?;
+            let t_state = t_state.lock().unwrap();
+            if let Some(db) = &t_state.db {
+                let msg = match quotes::restore(db, id) {
+                    Ok(true) => format!("Quote #{} restored.", id),
+                    _ => format!("Quote #{} isn't deleted.", id),
+                };
+                return Some(vec![msg]);
+            }
+            None
+        },
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
+        auth: Permissions::Streamer | Permissions::Mod,
+        usage: "quoterestore <id>",
+        description: "Brings back a removed quote.",
+        example: Some("quoterestore 3"),
+    }
+}
+
+// Permanently remove one deleted quote, or all of them
+fn quotepurge() -> Cmd {
+    Cmd {
+        func: |t_state, _, args| {
+            let id = match args {
+                Some(args) => Some(args.parse::<u32>().ok()?),
+                None => None,
+            };
+            let t_state = t_state.lock().unwrap();
+            if let Some(db) = &t_state.db {
+                let msg = match quotes::purge(db, id) {
+                    Ok(1) => String::from("Purged 1 deleted quote."),
+                    Ok(n) => format!("Purged {} deleted quotes.", n),
+                    Err(e) => format!("Purge failed: {}", e),
+                };
+                return Some(vec![msg]);
+            }
+            None
+        },
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
+        auth: Permissions::Owner,
+        usage: "quotepurge [id]",
+        description: "Deletes removed quotes for good, one or all of them.",
+        example: Some("quotepurge 3"),
+    }
+}
+
+fn role() -> Cmd {
+    Cmd {
+        func: |t_state, context, args| {
+            let usage = "Usage: !role add <role> <user> | rm <role> [user] | list [role] | cmd <command> <role>|none";
+            let (sub, args) = pop_cmd(&args?);
+            let mut args = args.as_ref().map_or("", String::as_str).split_whitespace();
+            let role = args.next().map(str::to_lowercase);
+            // Names are stored as logins, which Twitch keeps lowercase
+            let user = args.next().map(|user| user.trim_start_matches('@').to_lowercase());
+
+            let t_state = t_state.lock().unwrap();
+            let db = t_state.db.as_ref()?;
+            if let Some(role) = &role {
+                if !roles::valid_name(role) {
+                    return Some(vec![String::from("Role names are lowercase letters, digits, - and _.")]);
+                }
+            }
+            // Twitch names are given out again, so go by the id of whoever has the name now
+            let user_id = user.as_ref().and_then(|user| users::id_of(db, user).unwrap_or(None));
+            let user_id = user_id.as_ref().map(String::as_str);
+            let msg = match (sub.as_str(), role, user) {
+                ("add", Some(role), Some(user)) => {
+                    let added_by = context.get_sender_display().unwrap_or_else(|| context.sender.clone());
+                    match roles::grant(db, &role, user_id, &user, &added_by) {
+                        Ok(true) => format!("Added {} to {}.", user, role),
+                        Ok(false) => format!("{} is already in {}.", user, role),
+                        Err(e) => format!("Unable to add {} to {}: {}", user, role, e),
+                    }
+                }
+                ("rm", Some(role), Some(user)) => match roles::revoke(db, &role, user_id, &user) {
+                    Ok(true) => format!("Removed {} from {}.", user, role),
+                    Ok(false) => format!("{} isn't in {}.", user, role),
+                    Err(e) => format!("Unable to remove {} from {}: {}", user, role, e),
+                },
+                ("rm", Some(role), None) => match roles::remove(db, &role) {
+                    Ok(true) => format!("Removed the {} role.", role),
+                    Ok(false) => format!("There is no {} role.", role),
+                    Err(e) => format!("Unable to remove {}: {}", role, e),
+                },
+                ("list", Some(role), _) => match roles::members(db, &role) {
+                    Ok(Some(ref users)) if users.is_empty() => format!("Nobody is in {}.", role),
+                    Ok(Some(users)) => format!("{}: {}", role, users.join(", ")),
+                    Ok(None) => format!("There is no {} role.", role),
+                    Err(e) => format!("Unable to list {}: {}", role, e),
+                },
+                ("list", None, _) => match roles::list(db) {
+                    Ok(ref list) if list.is_empty() => String::from("There are no roles."),
+                    Ok(list) => {
+                        let list: Vec<String> = list.iter().map(|(role, n)| format!("{} ({})", role, n)).collect();
+                        format!("Roles: {}", list.join(", "))
+                    }
+                    Err(e) => format!("Unable to list roles: {}", e),
+                },
+                _ => String::from(usage),
+            };
+            Some(vec![msg])
+        },
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
         auth: Permissions::Streamer | Permissions::Mod,
+        usage: "role add <role> <user> | rm <role> [user] | list [role] | cmd <command> <role>|none",
+        description: "Manages the channel's roles, and which built-in commands they may use.",
+        example: Some("role add editors @ZedExV"),
     }
 }
 
@@ -790,12 +2007,74 @@ fn shutdown() -> Cmd {
     Cmd {
         func: |t_state, _, _| {
             let t_state = t_state.lock().unwrap();
-            let mut state = t_state.main.lock().unwrap();
-            state.shutdown = true;
+            let state = t_state.main.lock().unwrap();
+            let _ = state.events.send(Event::Shutdown);
             None
         },
         bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
+        auth: Permissions::Owner,
+        usage: "shutdown",
+        description: "Shuts the bot down.",
+        example: None,
+    }
+}
+
+fn reload() -> Cmd {
+    Cmd {
+        func: |t_state, _, _| {
+            let t_state = t_state.lock().unwrap();
+            let state = t_state.main.lock().unwrap();
+            let _ = state.events.send(Event::Reload);
+            None
+        },
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
+        auth: Permissions::Owner,
+        usage: "reload",
+        description: "Reloads the config file.",
+        example: None,
+    }
+}
+
+fn health() -> Cmd {
+    Cmd {
+        func: |t_state, _, _| {
+            let t_state = t_state.lock().unwrap();
+            let state = t_state.main.lock().unwrap();
+            let mut channels: Vec<_> = state.health.iter().collect();
+            channels.sort_by_key(|(name, _)| name.as_str());
+
+            let mut s = String::new();
+            for (name, health) in channels {
+                if !s.is_empty() {
+                    s.push_str(", ");
+                }
+                let status = match health.status {
+                    Status::Running => "running",
+                    Status::Restarting => "restarting",
+                    Status::Failed => "failed",
+                };
+                s.push_str(&format!("{}: {}", name, status));
+                if health.restarts > 0 {
+                    s.push_str(&format!(" ({} restarts", health.restarts));
+                    if let Some(crash) = &health.last_crash {
+                        s.push_str(&format!(", last crash: {}", crash));
+                    }
+                    s.push(')');
+                }
+            }
+            Some(vec![s])
+        },
+        bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
         auth: Permissions::Owner,
+        usage: "health",
+        description: "Shows how every channel is doing.",
+        example: None,
     }
 }
 
@@ -806,7 +2085,12 @@ fn version() -> Cmd {
             Some(vec![String::from(v)])
         },
         bucket: None,
+        user_bucket: None,
+        priority: Priority::Normal,
         auth: Permissions::Owner,
+        usage: "version",
+        description: "Shows the bot's version.",
+        example: None,
     }
 }
 
@@ -816,6 +2100,72 @@ fn version() -> Cmd {
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
 
+// The commands an alias runs, which are split by `;`. `;;` is a `;` that doesn't split them.
+fn alias_steps(command: &str) -> Vec<String> {
+    let mut steps = vec![String::new()];
+    let mut chars = command.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != ';' {
+            steps.last_mut().unwrap().push(ch);
+        } else if chars.peek() == Some(&';') {
+            chars.next();
+            steps.last_mut().unwrap().push(';');
+        } else {
+            steps.push(String::new());
+        }
+    }
+    steps
+        .into_iter()
+        .map(|step| step.trim().to_string())
+        .filter(|step| !step.is_empty())
+        .collect()
+}
+
+// One of those commands' name and arguments
+fn split_step(step: &str) -> (&str, Option<&str>) {
+    match step.find(' ') {
+        Some(space) => (&step[..space], Some(step[space + 1..].trim()).filter(|args| !args.is_empty())),
+        None => (step, None),
+    }
+}
+
+// The stricter of two cooldowns, a disabled one being the least strict
+fn stricter(a: Option<Bucket>, b: Option<&Bucket>) -> Option<Bucket> {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            if a.count == 0 || (b.count > 0 && b.rate() < a.rate()) {
+                Some(b.clone())
+            } else {
+                Some(a)
+            }
+        }
+        (a, b) => a.or_else(|| b.cloned()),
+    }
+}
+
+// Join sentences into as few messages as Twitch will take, cutting short any too long for one
+fn fit_messages(parts: Vec<String>) -> Vec<String> {
+    let mut msgv: Vec<String> = Vec::new();
+    for mut part in parts {
+        if part.len() > MAX_MSG_LEN {
+            let mut end = MAX_MSG_LEN - 3;
+            while !part.is_char_boundary(end) {
+                end -= 1;
+            }
+            part.truncate(end);
+            part.push_str("...");
+        }
+        match msgv.last_mut() {
+            Some(msg) if msg.len() + part.len() < MAX_MSG_LEN => {
+                msg.push(' ');
+                msg.push_str(&part);
+            }
+            _ => msgv.push(part),
+        }
+    }
+    msgv
+}
+
 fn pop_cmd(s: &str) -> (String, Option<String>) {
     let s = String::from(s.trim_left());
     let argv: Vec<&str> = s.splitn(2, ' ').collect();
@@ -827,26 +2177,326 @@ fn pop_cmd(s: &str) -> (String, Option<String>) {
     }
 }
 
+// `search <terms>` or `by <person> [terms]`
+fn quote_filter(sub: &str, args: Option<String>) -> Option<quotes::Filter> {
+    let args = args?;
+    if sub == "by" {
+        let (author, terms) = pop_cmd(&args);
+        Some(quotes::Filter {
+            terms: terms.filter(|terms| !terms.is_empty()),
+            author: Some(author.trim_start_matches('@').to_string()),
+        })
+    } else {
+        Some(quotes::Filter {
+            terms: Some(args),
+            author: None,
+        })
+    }
+}
+
+// One match of a search, numbered when there's more than one
+fn quote_page(db: &Connection, search: &quotes::Search, template: &str, prefix: char) -> Option<Vec<String>> {
+    if search.total == 0 {
+        return Some(vec![String::from("No quotes match.")]);
+    }
+    let quote = quotes::find(db, &search.filter, search.offset).ok()?.format(template);
+    let msg = if search.total == 1 {
+        quote
+    } else if search.offset == 0 {
+        format!("(1/{}, {}quote next for more) {}", search.total, prefix, quote)
+    } else {
+        format!("({}/{}) {}", search.offset + 1, search.total, quote)
+    };
+    Some(vec![msg])
+}
+
 fn rm_alias(db: &Connection, alias: &str) {
     let _ = db.execute("DELETE FROM alias WHERE alias=?1", &[&alias]);
 }
 
-fn add_alias(db: &Connection, alias: &str, auth: &Permissions, cmd: &str) {
+fn add_alias(db: &Connection, alias: &str, auth: &Permissions, cmd: &str, added_by: &str) {
     let _ = db.execute(
-        "INSERT INTO alias (auth, alias, command) VALUES (?1, ?2, ?3)",
-        &[&auth.bits(), &alias, &cmd],
+        "INSERT INTO alias (auth, alias, command, added_by, added_at) VALUES (?1, ?2, ?3, ?4, strftime('%s', 'now'))",
+        &[&auth.bits(), &alias, &cmd, &added_by],
     );
 }
 
-fn get_alias(db: &Connection, alias: &str) -> Option<(Permissions, String)> {
-    if let Ok((auth, cmd)) = db.query_row("SELECT * FROM alias WHERE alias=?1", &[&alias], |row| {
-        let auth: u8 = row.get(1);
-        let cmd: String = row.get(3);
-        (auth, cmd)
-    }) {
-        let auth = Permissions::from_bits(auth).unwrap();
-        Some((auth, cmd))
+struct Alias {
+    auth: Permissions,
+    command: String,
+    bucket: Option<Bucket>,
+    user_bucket: Option<Bucket>,
+    role: Option<String>,
+    args: ArgRules,
+    added_by: Option<String>,
+    // e.g. `2018-11-04`
+    added_on: Option<String>,
+}
+
+// What the arguments given to an alias have to look like
+#[derive(Clone, Default)]
+struct ArgRules {
+    min: Option<usize>,
+    max: Option<usize>,
+    // Regexes whole arguments have to match, by position counting from 1
+    patterns: BTreeMap<usize, String>,
+}
+
+impl ArgRules {
+    // Why `args` won't do, if they won't. Unless `min` says otherwise, it takes as many as the
+    // alias's placeholders need.
+    fn check(&self, args: &[&str], required: usize) -> Result<(), String> {
+        let min = self.min.unwrap_or(required);
+        if args.len() < min || self.max.map_or(false, |max| args.len() > max) {
+            let plural = |n| if n == 1 { "argument" } else { "arguments" };
+            return Err(match self.max {
+                Some(0) => String::from("takes no arguments"),
+                Some(max) if max == min => format!("takes {} {}", max, plural(max)),
+                Some(max) if min == 0 => format!("takes at most {} {}", max, plural(max)),
+                Some(max) => format!("takes {} to {} arguments", min, max),
+                None => format!("takes at least {} {}", min, plural(min)),
+            });
+        }
+        for (&index, pattern) in &self.patterns {
+            if let Some(arg) = index.checked_sub(1).and_then(|i| args.get(i)) {
+                // Checked when it was set, so a pattern that won't compile is only from a bad import
+                let valid = Regex::new(&format!("^(?:{})$", pattern)).map(|re| re.is_match(arg));
+                if !valid.unwrap_or(false) {
+                    return Err(format!("doesn't take `{}` as argument {}", arg, index));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // `2`, `1-3`, `1-` or `default`
+    fn parse_count(&mut self, s: &str) -> Option<()> {
+        if s == "default" {
+            self.min = None;
+            self.max = None;
+            return Some(());
+        }
+        let mut split = s.splitn(2, '-');
+        let min = split.next()?.parse::<usize>().ok()?;
+        let max = match split.next() {
+            None => Some(min),
+            Some("") => None,
+            Some(max) => Some(max.parse::<usize>().ok().filter(|&max| max >= min)?),
+        };
+        self.min = Some(min);
+        self.max = max;
+        Some(())
+    }
+
+    fn patterns_json(&self) -> Option<String> {
+        if self.patterns.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&self.patterns).ok()
+        }
+    }
+}
+
+// Columns read by `Alias::from_row`, in order
+const ALIAS_COLUMNS: &str = "auth, command, bucket_count, bucket_secs, user_bucket_count, user_bucket_secs, role, \
+                             min_args, max_args, arg_patterns, added_by, date(added_at, 'unixepoch')";
+
+impl Alias {
+    fn from_row(row: &Row) -> Alias {
+        let auth: u32 = row.get(0);
+        let bucket = match (row.get::<_, Option<u32>>(2), row.get::<_, Option<i64>>(3)) {
+            (Some(count), Some(secs)) => Some(Bucket::new(count, secs as u64)),
+            _ => None,
+        };
+        let user_bucket = match (row.get::<_, Option<u32>>(4), row.get::<_, Option<i64>>(5)) {
+            (Some(count), Some(secs)) => Some(Bucket::new(count, secs as u64)),
+            _ => None,
+        };
+        Alias {
+            auth: Permissions::from_bits_truncate(auth),
+            command: row.get(1),
+            bucket,
+            user_bucket,
+            role: row.get(6),
+            args: ArgRules {
+                min: row.get::<_, Option<i64>>(7).map(|n| n as usize),
+                max: row.get::<_, Option<i64>>(8).map(|n| n as usize),
+                patterns: row
+                    .get::<_, Option<String>>(9)
+                    .and_then(|json| serde_json::from_str(&json).ok())
+                    .unwrap_or_default(),
+            },
+            added_by: row.get(10),
+            added_on: row.get(11),
+        }
+    }
+}
+
+fn get_alias(db: &Connection, alias: &str) -> Option<Alias> {
+    let sql = format!("SELECT {} FROM alias WHERE alias=?1", ALIAS_COLUMNS);
+    db.query_row(&sql, &[&alias], Alias::from_row).ok()
+}
+
+fn all_aliases(db: &Connection) -> HashMap<String, Alias> {
+    // The name goes last, after what `Alias::from_row` reads
+    let sql = format!("SELECT {}, alias FROM alias", ALIAS_COLUMNS);
+    let mut stmt = match db.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(_) => return HashMap::new(),
+    };
+    let rows = stmt.query_map(&[], |row| (row.get::<_, String>(12), Alias::from_row(row)));
+    match rows {
+        Ok(rows) => rows.filter_map(Result::ok).collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+// Whether the caller's badges, or the role the command was opened up to with `!role cmd`, let them
+// use a built-in command
+fn may_run(db: Option<&Connection>, context: &Context, name: &str, c: &Cmd) -> bool {
+    context.auth.intersects(c.auth)
+        || db.map_or(false, |db| match roles::command_role(db, name) {
+            Ok(Some(role)) => roles::has(db, &role, context.user_id(), &context.sender).unwrap_or(false),
+            _ => false,
+        })
+}
+
+// Why `role` can't be given to a command or alias, if it can't
+fn bad_role(db: &Connection, role: &str) -> Option<String> {
+    if !roles::valid_name(role) {
+        Some(String::from("Role names are lowercase letters, digits, - and _."))
+    } else if !roles::exists(db, role).unwrap_or(false) {
+        Some(format!("There is no {} role.", role))
     } else {
         None
     }
 }
+
+// Whether the caller's badges or role let them use an alias
+fn may_use(db: &Connection, context: &Context, alias: &Alias) -> bool {
+    context.auth.intersects(alias.auth)
+        || alias.role.as_ref().map_or(false, |role| {
+            roles::has(db, role, context.user_id(), &context.sender).unwrap_or(false)
+        })
+}
+
+fn set_alias_buckets(db: &Connection, alias: &str, bucket: Option<&Bucket>, user_bucket: Option<&Bucket>) {
+    let count = bucket.map(|b| b.count);
+    let secs = bucket.map(|b| b.interval.as_secs() as i64);
+    let user_count = user_bucket.map(|b| b.count);
+    let user_secs = user_bucket.map(|b| b.interval.as_secs() as i64);
+    let _ = db.execute(
+        "UPDATE alias SET bucket_count=?1, bucket_secs=?2, user_bucket_count=?3, user_bucket_secs=?4
+         WHERE alias=?5",
+        &[&count, &secs, &user_count, &user_secs, &alias],
+    );
+}
+
+fn set_alias_args(db: &Connection, alias: &str, rules: &ArgRules) {
+    let min = rules.min.map(|n| n as i64);
+    let max = rules.max.map(|n| n as i64);
+    let _ = db.execute(
+        "UPDATE alias SET min_args=?1, max_args=?2, arg_patterns=?3 WHERE alias=?4",
+        &[&min, &max, &rules.patterns_json(), &alias],
+    );
+}
+
+fn as_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps() {
+        assert_eq!(alias_steps("say hi"), vec!["say hi"]);
+        assert_eq!(alias_steps("say a; so $1"), vec!["say a", "so $1"]);
+        assert_eq!(alias_steps(" a ;; b ; ; c;"), vec!["a ; b", "c"]);
+        assert_eq!(alias_steps("a;;;b"), vec!["a;", "b"]);
+        assert_eq!(alias_steps("a;;;;b"), vec!["a;;b"]);
+        assert!(alias_steps(" ; ").is_empty());
+    }
+
+    #[test]
+    fn step_parts() {
+        assert_eq!(split_step("say hi there"), ("say", Some("hi there")));
+        assert_eq!(split_step("say  "), ("say", None));
+        assert_eq!(split_step("quote"), ("quote", None));
+    }
+
+    fn count(s: &str) -> Option<(Option<usize>, Option<usize>)> {
+        let mut rules = ArgRules::default();
+        rules.parse_count(s).map(|()| (rules.min, rules.max))
+    }
+
+    #[test]
+    fn parse_count() {
+        assert_eq!(count("2"), Some((Some(2), Some(2))));
+        assert_eq!(count("0"), Some((Some(0), Some(0))));
+        assert_eq!(count("1-3"), Some((Some(1), Some(3))));
+        assert_eq!(count("2-2"), Some((Some(2), Some(2))));
+        assert_eq!(count("1-"), Some((Some(1), None)));
+        assert_eq!(count("default"), Some((None, None)));
+        for bad in &["", "-", "-1", "3-1", "x", "1-x", "1-2-3", "1.5", " 1"] {
+            assert_eq!(count(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn bad_count_changes_nothing() {
+        let mut rules = ArgRules::default();
+        rules.parse_count("1-3").unwrap();
+        assert!(rules.parse_count("3-1").is_none());
+        assert_eq!((rules.min, rules.max), (Some(1), Some(3)));
+    }
+
+    #[test]
+    fn check_counts() {
+        let rules = ArgRules::default();
+        assert!(rules.check(&[], 0).is_ok());
+        assert!(rules.check(&["a", "b"], 0).is_ok());
+        assert_eq!(rules.check(&["a"], 2), Err(String::from("takes at least 2 arguments")));
+
+        let mut rules = ArgRules::default();
+        rules.parse_count("2").unwrap();
+        assert!(rules.check(&["a", "b"], 5).is_ok());
+        assert_eq!(rules.check(&["a"], 0), Err(String::from("takes 2 arguments")));
+        rules.parse_count("0").unwrap();
+        assert_eq!(rules.check(&["a"], 0), Err(String::from("takes no arguments")));
+        rules.parse_count("0-1").unwrap();
+        assert_eq!(rules.check(&["a", "b"], 0), Err(String::from("takes at most 1 argument")));
+        rules.parse_count("1-2").unwrap();
+        assert_eq!(rules.check(&[], 0), Err(String::from("takes 1 to 2 arguments")));
+    }
+
+    #[test]
+    fn check_patterns() {
+        let mut rules = ArgRules::default();
+        rules.patterns.insert(2, String::from(r"\d+"));
+        assert!(rules.check(&["a", "12"], 0).is_ok());
+        assert!(rules.check(&["a"], 0).is_ok());
+        // The whole argument has to match
+        assert_eq!(rules.check(&["a", "12x"], 0), Err(String::from("doesn't take `12x` as argument 2")));
+        assert!(rules.check(&["a", "1|x"], 0).is_err());
+
+        // Only from a bad import, and neither may panic
+        rules.patterns.insert(0, String::from("x"));
+        rules.patterns.insert(3, String::from("("));
+        assert!(rules.check(&["a", "1"], 0).is_ok());
+        assert!(rules.check(&["a", "1", "b"], 0).is_err());
+    }
+
+    #[test]
+    fn fit() {
+        assert_eq!(fit_messages(vec![String::from("a."), String::from("b.")]), vec!["a. b."]);
+        let long = "x".repeat(MAX_MSG_LEN + 10);
+        let msgv = fit_messages(vec![String::from("a."), long, String::from("b.")]);
+        assert_eq!(msgv.len(), 3);
+        assert_eq!(msgv[1].len(), MAX_MSG_LEN);
+        assert!(msgv[1].ends_with("..."));
+        let wide = "é".repeat(MAX_MSG_LEN);
+        assert!(fit_messages(vec![wide]).iter().all(|msg| msg.len() <= MAX_MSG_LEN));
+    }
+}
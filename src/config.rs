@@ -1,69 +1,243 @@
-use std::{env, process};
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::{DirBuilder, File};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use toml;
 
 #[derive(Clone, Deserialize, Debug)]
 pub struct Config {
     pub user: String,
     pub pass: String,
-    pub owners: Vec<String>,
+    pub owners: Vec<Owner>,
+    // Join every channel over one connection instead of one connection per channel
+    #[serde(default)]
+    pub shared_connection: bool,
     pub channels: HashMap<String, Channel>,
 }
 
 impl Config {
-    pub fn open(path: &PathBuf) -> Config {
-        // Open config.toml
-        let mut file = File::open(&path).unwrap_or_else(|_| {
-            if let Ok(dir) = env::current_dir() {
-                println!("`{}/config.toml` does not exist.", dir.to_str().unwrap());
-            } else {
-                println!(
-                    "Current directory does not exist, or you do not have sufficient permissions."
-                );
-            }
-
-            process::exit(1);
-        });
-
-        // Read contents of config.toml and serialize
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        let mut cfg: Config = toml::from_str(&contents).unwrap();
+    // Load config.toml and set up the directories each channel keeps its database and log in.
+    // Logs go in the channel's data directory unless a separate log directory is given.
+    pub fn open(path: &Path, data_dir: &Path, log_dir: Option<&Path>) -> Result<Config, Vec<ConfigError>> {
+        let mut cfg = Config::load(path)?;
 
         // FIXME: Surely there's a better way to do this
         // Recreate each channel with config dir
         let mut channels = HashMap::new();
-        for mut chan in cfg.channels.values() {
-            let mut path = PathBuf::from(path.parent().unwrap());
-            path.push(format!("data/{}", chan.name.to_lowercase()));
-            if !path.exists() {
-                DirBuilder::new().recursive(true).create(&path).unwrap();
+        for chan in cfg.channels.values() {
+            let name = chan.name.to_lowercase();
+            let dir = data_dir.join(&name);
+            let log_dir = log_dir.map_or_else(|| dir.clone(), |log_dir| log_dir.join(&name));
+            for dir in &[&dir, &log_dir] {
+                if !dir.exists() {
+                    DirBuilder::new().recursive(true).create(dir).map_err(|e| {
+                        vec![ConfigError::new(None, format!("unable to create `{}`: {}", dir.display(), e))]
+                    })?;
+                }
             }
 
             channels.insert(
                 chan.name.clone(),
                 Channel {
-                    dir: path,
-                    name: chan.name.clone(),
-                    cmd_prefix: chan.cmd_prefix,
+                    dir,
+                    log_dir,
+                    ..chan.clone()
                 },
             );
         }
         cfg.channels = channels;
-        cfg
+        Ok(cfg)
     }
+
+    // Read and validate config.toml, without creating anything
+    pub fn load(path: &Path) -> Result<Config, Vec<ConfigError>> {
+        // Open config.toml
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| vec![ConfigError::new(None, format!("unable to read `{}`: {}", path.display(), e))])?;
+
+        let cfg = Config::parse(&contents).map_err(|e| vec![e])?;
+        let errors = cfg.validate(&contents);
+        if errors.is_empty() {
+            Ok(cfg)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+        toml::from_str(contents).map_err(|e| {
+            // toml counts lines from zero
+            let line = e.line_col().map(|(line, _)| line + 1);
+            let mut msg = e.to_string();
+            if let Some(line) = line {
+                // The line is reported separately
+                let suffix = format!(" at line {}", line);
+                if msg.ends_with(&suffix) {
+                    let len = msg.len() - suffix.len();
+                    msg.truncate(len);
+                }
+            }
+            ConfigError::new(line, msg)
+        })
+    }
+
+    // Catch mistakes that are valid TOML but won't work. `contents` is only used to find the line
+    // each problem is on.
+    pub fn validate(&self, contents: &str) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let key_line = |key: &str| {
+            find_line(contents, |line| line.starts_with(key) && line[key.len()..].trim_start().starts_with('='))
+        };
+
+        if self.user.is_empty() {
+            errors.push(ConfigError::new(key_line("user"), String::from("`user` must not be empty")));
+        }
+        if !self.pass.starts_with("oauth:") {
+            let msg = String::from("`pass` must be an oauth token starting with `oauth:`");
+            errors.push(ConfigError::new(key_line("pass"), msg));
+        }
+        for owner in &self.owners {
+            if let Some(id) = owner.id() {
+                if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+                    let line = find_line(contents, |line| line.contains(&format!("\"{}\"", id)));
+                    let msg = format!("owner `{}`: `{}` is not a user-id", owner.name(), id);
+                    errors.push(ConfigError::new(line, msg));
+                }
+            }
+        }
+        if self.channels.is_empty() {
+            errors.push(ConfigError::new(None, String::from("no channels are configured")));
+        }
+
+        let mut keys: Vec<&String> = self.channels.keys().collect();
+        keys.sort();
+        let mut names = HashMap::new();
+        for key in keys {
+            let chan = &self.channels[key];
+            let header = format!("[channels.{}]", key);
+            let line = find_line(contents, |line| line == header);
+            let mut invalid = |msg: String| {
+                errors.push(ConfigError::new(line, format!("channel `{}`: {}", key, msg)))
+            };
+
+            if chan.name.is_empty() {
+                invalid(String::from("`name` must not be empty"));
+            } else if chan.name.starts_with('#') || chan.name.contains(char::is_whitespace) {
+                invalid(format!("`{}` is not a valid channel name", chan.name));
+            } else if let Some(other) = names.insert(chan.name.to_lowercase(), key) {
+                invalid(format!("`{}` is already configured as `{}`", chan.name, other));
+            }
+            if chan.cmd_prefix.is_whitespace() {
+                invalid(String::from("`cmd_prefix` must not be whitespace"));
+            }
+            if chan.queue_size == 0 {
+                invalid(String::from("`queue_size` must be at least 1"));
+            }
+        }
+        errors
+    }
+}
+
+// Someone trusted with every command. Listing them by login still works, but trusts whoever holds
+// that name, so `{ id = "12345", name = "..." }` pins them to their Twitch user-id instead. TOML
+// arrays can't mix strings and tables, so `{ name = "..." }` is a login too.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Owner {
+    Table { name: String, id: Option<String> },
+    Name(String),
 }
 
+impl Owner {
+    // Who to whisper
+    pub fn name(&self) -> &str {
+        match self {
+            Owner::Table { name, .. } => name,
+            Owner::Name(name) => name,
+        }
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Owner::Table { id, .. } => id.as_ref().map(String::as_str),
+            Owner::Name(_) => None,
+        }
+    }
+
+    pub fn is(&self, user_id: Option<&str>, login: &str) -> bool {
+        match self.id() {
+            Some(id) => user_id == Some(id),
+            None => login == self.name(),
+        }
+    }
+}
+
+// Where the config was loaded from, so it can be loaded again
+#[derive(Clone, Debug)]
+pub struct ConfigPaths {
+    pub path: PathBuf,
+    pub data_dir: PathBuf,
+    pub log_dir: Option<PathBuf>,
+}
+
+impl ConfigPaths {
+    pub fn open(&self) -> Result<Config, Vec<ConfigError>> {
+        Config::open(&self.path, &self.data_dir, self.log_dir.as_ref().map(PathBuf::as_path))
+    }
+}
+
+// A problem with config.toml, and the line it was found on if known
+#[derive(Debug)]
+pub struct ConfigError {
+    pub line: Option<usize>,
+    pub msg: String,
+}
+
+impl ConfigError {
+    fn new(line: Option<usize>, msg: String) -> Self {
+        Self { line, msg }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.msg),
+            None => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+// First line (counting from one) that matches once trimmed
+fn find_line<F: Fn(&str) -> bool>(contents: &str, pred: F) -> Option<usize> {
+    contents.lines().position(|line| pred(line.trim())).map(|i| i + 1)
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
 #[serde(default)]
-#[derive(Clone, Deserialize, Debug)]
 pub struct Channel {
     #[serde(skip)]
     pub dir: PathBuf,
+    #[serde(skip)]
+    pub log_dir: PathBuf,
     pub name: String,
     pub cmd_prefix: char,
+    pub cooldown_notice: CooldownNotice,
+    pub queue_size: usize,
+    pub queue_overflow: QueueOverflow,
+    // Said in the channel when the bot shuts down
+    pub part_message: Option<String>,
+    // How !quote shows a quote, see `Quote::format`
+    pub quote_format: String,
+}
+
+impl Channel {
+    pub fn db_path(&self) -> PathBuf {
+        self.dir.join("db")
+    }
 }
 
 impl Default for Channel {
@@ -71,7 +245,31 @@ impl Default for Channel {
         Self {
             name: String::from(""),
             cmd_prefix: '!',
+            cooldown_notice: CooldownNotice::None,
+            queue_size: 50,
+            queue_overflow: QueueOverflow::DropLowest,
+            part_message: None,
+            quote_format: String::from("[{id}] {quote}"),
             dir: PathBuf::new(),
+            log_dir: PathBuf::new(),
         }
     }
 }
+
+// How a user is told that the command they invoked is on cooldown
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CooldownNotice {
+    None,
+    Reply,
+    Whisper,
+}
+
+// What to lose when a channel's outbound queue is full
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOverflow {
+    DropNewest,
+    DropOldest,
+    DropLowest,
+}
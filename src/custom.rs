@@ -0,0 +1,192 @@
+use auth::Permissions;
+use rand::prelude::*;
+use rusqlite::{self, Connection};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A command made in chat with !cmd, which replies with its response filled in by `render`
+pub struct CustomCmd {
+    pub response: String,
+    pub auth: Permissions,
+}
+
+pub fn get(db: &Connection, name: &str) -> rusqlite::Result<CustomCmd> {
+    db.query_row("SELECT response, auth FROM custom_cmd WHERE name=?1", &[&name], |row| CustomCmd {
+        response: row.get(0),
+        auth: Permissions::from_bits_truncate(row.get(1)),
+    })
+}
+
+pub fn names(db: &Connection) -> rusqlite::Result<Vec<(String, Permissions)>> {
+    let mut stmt = db.prepare("SELECT name, auth FROM custom_cmd")?;
+    let rows = stmt.query_map(&[], |row| (row.get(0), Permissions::from_bits_truncate(row.get(1))))?;
+    rows.collect()
+}
+
+// False if the name is taken
+pub fn add(db: &Connection, name: &str, response: &str, auth: Permissions, added_by: &str) -> rusqlite::Result<bool> {
+    let n = db.execute(
+        "INSERT OR IGNORE INTO custom_cmd (name, response, auth, added_by, added_at)
+         VALUES (?1, ?2, ?3, ?4, strftime('%s', 'now'))",
+        &[&name, &response, &auth.bits(), &added_by],
+    )?;
+    Ok(n > 0)
+}
+
+// Replace the response, keeping the count. False if there's no such command.
+pub fn edit(db: &Connection, name: &str, response: &str) -> rusqlite::Result<bool> {
+    let n = db.execute("UPDATE custom_cmd SET response=?1 WHERE name=?2", &[&response, &name])?;
+    Ok(n > 0)
+}
+
+pub fn remove(db: &Connection, name: &str) -> rusqlite::Result<bool> {
+    let n = db.execute("DELETE FROM custom_cmd WHERE name=?1", &[&name])?;
+    Ok(n > 0)
+}
+
+// Count a use of the command, returning how many there have been
+pub fn bump(db: &Connection, name: &str) -> rusqlite::Result<u32> {
+    db.execute("UPDATE custom_cmd SET count = count + 1 WHERE name=?1", &[&name])?;
+    db.query_row("SELECT count FROM custom_cmd WHERE name=?1", &[&name], |row| {
+        row.get::<_, i64>(0) as u32
+    })
+}
+
+// What a response's variables are filled in with
+pub struct Vars<'a> {
+    // Display name of whoever used the command
+    pub user: &'a str,
+    pub args: Option<&'a str>,
+    pub channel: &'a str,
+    pub count: u32,
+}
+
+// Fill in `$(user)`, `$(touser)`, `$(args)`, `$(1)`, `$(2)`..., `$(channel)`, `$(count)`,
+// `$(random a|b|c)` and `$(time)`. Anything else is left as it is. What's filled in isn't looked at
+// again, so arguments can't smuggle in variables of their own.
+pub fn render(response: &str, vars: &Vars) -> String {
+    let args: Vec<&str> = vars.args.map_or_else(Vec::new, |args| args.split_whitespace().collect());
+    let mut out = String::with_capacity(response.len());
+    let mut rest = response;
+    while let Some(start) = rest.find("$(") {
+        out.push_str(&rest[..start]);
+        let var = &rest[start + 2..];
+        let end = match var.find(')') {
+            Some(end) => end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let (name, arg) = match var[..end].find(' ') {
+            Some(space) => (&var[..space], Some(var[space + 1..end].trim())),
+            None => (&var[..end], None),
+        };
+
+        // Only what users typed needs checking, the rest comes from whoever made the command
+        let value = match (name, arg) {
+            ("user", None) => vars.user.to_string(),
+            ("touser", None) => match args.first() {
+                Some(user) => guard(&out, user.trim_start_matches('@')),
+                None => vars.user.to_string(),
+            },
+            ("args", None) => guard(&out, vars.args.unwrap_or("")),
+            ("channel", None) => vars.channel.to_string(),
+            ("count", None) => vars.count.to_string(),
+            ("random", Some(choices)) => {
+                let choices: Vec<&str> = choices.split('|').map(str::trim).collect();
+                choices[thread_rng().gen_range(0, choices.len())].to_string()
+            }
+            ("time", None) => time(),
+            _ => match name.parse::<usize>() {
+                Ok(n) if n > 0 && arg.is_none() => guard(&out, args.get(n - 1).map_or("", |arg| *arg)),
+                _ => rest[start..start + end + 3].to_string(),
+            },
+        };
+        out.push_str(&value);
+        rest = &var[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Twitch runs messages starting with `/` or `.` as chat commands, so what a user typed can't be
+// allowed to start the response. `out` is what comes before `value`.
+pub fn guard(out: &str, value: &str) -> String {
+    if out.trim().is_empty() {
+        value.trim_start_matches(|c: char| c == '/' || c == '.' || c.is_whitespace()).to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+// e.g. `14:05 UTC`
+fn time() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    format!("{:02}:{:02} UTC", secs / 3600 % 24, secs / 60 % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_args(response: &str, args: Option<&str>) -> String {
+        let vars = Vars {
+            user: "Zed",
+            args,
+            channel: "chan",
+            count: 3,
+        };
+        render(response, &vars)
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(render_args("$(user) in $(channel), $(count) times", None), "Zed in chan, 3 times");
+        assert_eq!(render_args("$(user) hugs $(touser)", Some("@Amy and co")), "Zed hugs Amy");
+        assert_eq!(render_args("$(user) hugs $(touser)", None), "Zed hugs Zed");
+        assert_eq!(render_args("[$(args)]", Some("a  b")), "[a  b]");
+        assert_eq!(render_args("$(2) $(1) $(3)", Some("a b")), "b a ");
+    }
+
+    #[test]
+    fn left_alone() {
+        assert_eq!(render_args("$(0) $(nope) $(user x) $(1 x)", Some("a")), "$(0) $(nope) $(user x) $(1 x)");
+        assert_eq!(render_args("hi $(user", None), "hi $(user");
+        assert_eq!(render_args("$(user) $(", None), "Zed $(");
+        assert_eq!(render_args("$ (user) $user", None), "$ (user) $user");
+    }
+
+    #[test]
+    fn random() {
+        for _ in 0..20 {
+            let out = render_args("$(random a | b|c)", None);
+            assert!(out == "a" || out == "b" || out == "c", "{}", out);
+        }
+        assert_eq!(render_args("$(random only)", None), "only");
+    }
+
+    #[test]
+    fn not_rendered_twice() {
+        assert_eq!(render_args("say $(args)", Some("$(user) $(count)")), "say $(user) $(count)");
+        assert_eq!(render_args("$(1)", Some("$(random")), "$(random");
+    }
+
+    #[test]
+    fn guarded() {
+        assert_eq!(render_args("$(args)", Some("/ban Zed")), "ban Zed");
+        assert_eq!(render_args("$(1)", Some(".timeout")), "timeout");
+        assert_eq!(render_args("$(touser)", Some("/clear")), "clear");
+        assert_eq!(render_args(" $(args)", Some(" /clear")), " clear");
+        assert_eq!(render_args("hug $(args)", Some("/clear")), "hug /clear");
+        assert_eq!(render_args("/me waves at $(touser)", Some("/clear")), "/me waves at /clear");
+    }
+
+    #[test]
+    fn guard_only_at_start() {
+        assert_eq!(guard("", "/x"), "x");
+        assert_eq!(guard("  ", " ./x"), "x");
+        assert_eq!(guard("a", "/x"), "/x");
+    }
+}
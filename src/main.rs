@@ -5,28 +5,137 @@ extern crate serde_derive;
 extern crate serde_json;
 #[macro_use]
 extern crate bitflags;
-extern crate rb;
 extern crate rusqlite;
 extern crate rand;
 extern crate regex;
 extern crate curl;
 extern crate dirs;
+#[macro_use]
+extern crate clap;
+extern crate signal_hook;
+extern crate csv;
 
 mod auth;
+mod backoff;
 mod config;
+mod custom;
 mod cmd;
+mod queue;
+mod quotes;
+mod roles;
+mod schema;
 mod state;
 mod twitch;
 mod strawpoll;
+mod supervisor;
+mod tags;
+mod template;
+mod transfer;
+mod users;
 
-use std::path::PathBuf;
-use std::sync::Arc;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use signal_hook::iterator::Signals;
+use std::sync::mpsc::{self, Sender};
 use std::thread;
+use supervisor::Event;
 
 fn main() {
-    // TODO: Add cli parsing
-    // TODO: Changeable config dir
+    let matches = App::new("zbot")
+        .version(crate_version!())
+        .about("A Twitch chat bot")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Config file to use [default: $XDG_CONFIG_HOME/zbot/config.toml]"),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Where channel databases are kept [default: <config dir>/data]"),
+        )
+        .arg(
+            Arg::with_name("log-dir")
+                .long("log-dir")
+                .value_name("DIR")
+                .help("Where channel logs are kept [default: the data dir]"),
+        )
+        .subcommand(SubCommand::with_name("run").about("Connect to the configured channels (default)"))
+        .subcommand(SubCommand::with_name("check-config").about("Validate the config file and exit"))
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write a channel's quotes, aliases and custom commands out")
+                .arg(Arg::with_name("channel").required(true).help("Channel name"))
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .possible_values(&["json", "csv"])
+                        .default_value("json"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .possible_values(&["quotes", "aliases", "custom"])
+                        .help("Only export this table [default: all of them, or quotes for CSV]"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .value_name("FILE")
+                        .help("Write here instead of stdout"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Add quotes, aliases and custom commands to a channel, skipping duplicates")
+                .arg(Arg::with_name("channel").required(true).help("Channel name"))
+                .arg(Arg::with_name("file").required(true).help("File to import"))
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .possible_values(&["json", "csv", "nightbot", "streamlabs"])
+                        .default_value("json"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .possible_values(&["quotes", "aliases", "custom"])
+                        .help("Only import this table [default: all of them, or quotes for CSV]"),
+                )
+                .arg(
+                    Arg::with_name("legacy")
+                        .long("legacy")
+                        .help("The CSV file's aliases are from before `;` and `$` meant anything in them"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Report what would be imported without changing anything"),
+                ),
+        )
+        .get_matches();
+
+    let cfg_file = match matches.value_of("config") {
+        Some(path) => PathBuf::from(path),
+        None => default_config(),
+    };
+
+    match matches.subcommand() {
+        ("check-config", _) => check_config(&cfg_file, &matches),
+        ("export", Some(sub)) => export(&cfg_file, &matches, sub),
+        ("import", Some(sub)) => import(&cfg_file, &matches, sub),
+        _ => run(&cfg_file, &matches),
+    }
+}
 
+fn default_config() -> PathBuf {
     // Set config path
     let mut cfg_path;
     if let Ok(dir) = std::env::var("XDG_CONFIG_HOME") {
@@ -45,43 +154,190 @@ fn main() {
             .unwrap();
     }
 
-    // If cfg_file doesn't exist, panic
-    let mut cfg_file = cfg_path.clone();
-    cfg_file.push("config.toml");
+    cfg_path.push("config.toml");
+    cfg_path
+}
+
+fn check_config(cfg_file: &Path, matches: &ArgMatches) {
+    match config::Config::load(cfg_file) {
+        Ok(cfg) => {
+            println!("{}: OK ({} channels)", cfg_file.display(), cfg.channels.len());
+            suggest_owner_ids(&cfg, &data_dir(cfg_file, matches));
+        }
+        Err(errors) => {
+            for e in errors {
+                eprintln!("{}: {}", cfg_file.display(), e);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+// Owners listed by login should be pinned to their user-id. Channel databases record the ids of
+// everyone seen in chat, so look there for what to write instead.
+fn suggest_owner_ids(cfg: &config::Config, data_dir: &Path) {
+    for owner in &cfg.owners {
+        if owner.id().is_some() {
+            continue;
+        }
+        let name = owner.name().to_lowercase();
+        // Read only, and without migrating; a database too old to know ids just doesn't help
+        let id = cfg.channels.values().find_map(|chan| {
+            let path = data_dir.join(chan.name.to_lowercase()).join("db");
+            let db = rusqlite::Connection::open_with_flags(&path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY).ok()?;
+            users::id_of(&db, &name).ok()?
+        });
+        match id {
+            Some(id) => println!(
+                "owner `{}` is listed by login, write {{ id = \"{}\", name = \"{}\" }} to pin them to their user-id",
+                name, id, name
+            ),
+            None => println!(
+                "owner `{}` is listed by login; their user-id will be known once they chat in one of the channels",
+                name
+            ),
+        }
+    }
+}
+
+fn data_dir(cfg_file: &Path, matches: &ArgMatches) -> PathBuf {
+    match matches.value_of("data-dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => cfg_file.parent().unwrap_or_else(|| Path::new(".")).join("data"),
+    }
+}
+
+// Load the config, exiting if it's missing or has errors
+fn open_config(cfg_file: &Path, matches: &ArgMatches) -> (config::ConfigPaths, config::Config) {
+    let data_dir = data_dir(cfg_file, matches);
+    let log_dir = matches.value_of("log-dir").map(PathBuf::from);
+
+    // If cfg_file doesn't exist, exit
     if !cfg_file.exists() {
-        println!(
+        eprintln!(
             "Config file `{}` does not exist.",
-            cfg_file.to_str().unwrap()
+            cfg_file.display()
         );
         std::process::exit(1);
     }
 
-    let cfg = config::Config::open(&cfg_file);
-    let state = state::MainState::new();
-
-    let mut threads = Vec::new();
-    for channel in cfg.channels.values() {
-        // Create local copies of variables
-        let user = cfg.user.clone();
-        let pass = cfg.pass.clone();
-        let owners = cfg.owners.clone();
-        let channel = channel.clone();
-        let t_state = state::ThreadState::new(Arc::clone(&state));
-
-        // Spawn thread
-        threads.push(thread::spawn(move || {
-            twitch::init(&t_state, &channel, &owners, &user, &pass);
-        }));
+    let paths = config::ConfigPaths {
+        path: cfg_file.to_path_buf(),
+        data_dir,
+        log_dir,
+    };
+    match paths.open() {
+        Ok(cfg) => (paths, cfg),
+        Err(errors) => {
+            for e in errors {
+                eprintln!("{}: {}", cfg_file.display(), e);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+// Open the database of a configured channel, exiting if there's no such channel
+fn open_channel_db(cfg_file: &Path, matches: &ArgMatches, name: &str) -> rusqlite::Connection {
+    let (_, cfg) = open_config(cfg_file, matches);
+    let channel = match cfg.channels.values().find(|c| c.name.eq_ignore_ascii_case(name)) {
+        Some(channel) => channel,
+        None => {
+            eprintln!("Channel `{}` is not in {}", name, cfg_file.display());
+            std::process::exit(1);
+        }
+    };
+    let db_path = channel.db_path();
+    match schema::open(&db_path) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("{}: {}", db_path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn export(cfg_file: &Path, matches: &ArgMatches, sub: &ArgMatches) {
+    let db = open_channel_db(cfg_file, matches, sub.value_of("channel").unwrap());
+    let format = transfer::Format::parse(sub.value_of("format").unwrap()).unwrap();
+    let tables = match sub.value_of("table").and_then(transfer::Table::parse) {
+        Some(table) => vec![table],
+        None if format == transfer::Format::Csv => vec![transfer::Table::Quotes],
+        None => vec![transfer::Table::Quotes, transfer::Table::Aliases, transfer::Table::Custom],
+    };
+
+    let result = match sub.value_of("output") {
+        Some(path) => File::create(path)
+            .map_err(transfer::TransferError::from)
+            .and_then(|file| transfer::export(&db, &tables, format, file)),
+        None => transfer::export(&db, &tables, format, std::io::stdout()),
+    };
+    if let Err(e) = result {
+        eprintln!("Export failed: {}", e);
+        std::process::exit(1);
     }
+}
+
+fn import(cfg_file: &Path, matches: &ArgMatches, sub: &ArgMatches) {
+    let mut db = open_channel_db(cfg_file, matches, sub.value_of("channel").unwrap());
+    let format = transfer::Format::parse(sub.value_of("format").unwrap()).unwrap();
+    let table = sub.value_of("table").and_then(transfer::Table::parse);
+    let legacy = sub.is_present("legacy");
+    let dry_run = sub.is_present("dry-run");
 
-    loop {
-        {
-            // Lock state and check for shutdown
-            let state = state.lock().unwrap();
-            if state.shutdown {
-                std::process::exit(0)
+    let path = sub.value_of("file").unwrap();
+    let result = File::open(path)
+        .map_err(transfer::TransferError::from)
+        .and_then(|file| transfer::import(&mut db, table, format, file, legacy, dry_run));
+    match result {
+        Ok(report) => {
+            print!("{}", report);
+            if dry_run {
+                println!("Dry run, nothing was changed.");
             }
-        } // Unlock state
-        thread::sleep(std::time::Duration::from_secs(1));
+        }
+        Err(e) => {
+            eprintln!("{}: import failed: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run(cfg_file: &Path, matches: &ArgMatches) {
+    let (paths, cfg) = open_config(cfg_file, matches);
+    for owner in &cfg.owners {
+        if owner.id().is_none() {
+            println!("Warning: owner `{}` is listed by login, see `zbot check-config`", owner.name());
+        }
     }
+    let (events, rx) = mpsc::channel();
+    let state = state::MainState::new(&events);
+    handle_signals(&events);
+
+    // Run the channels until something asks for a shutdown
+    let mut supervisor = supervisor::Supervisor::new(paths, cfg, &state, rx);
+    supervisor.run();
+}
+
+// Reload the config on SIGHUP, and shut down cleanly on SIGINT or SIGTERM
+fn handle_signals(events: &Sender<Event>) {
+    let signals = match Signals::new(&[signal_hook::SIGHUP, signal_hook::SIGINT, signal_hook::SIGTERM]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            println!("Unable to handle signals: {}", e);
+            return;
+        }
+    };
+    let events = events.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let event = match signal {
+                signal_hook::SIGHUP => Event::Reload,
+                _ => Event::Shutdown,
+            };
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    });
 }
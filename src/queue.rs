@@ -0,0 +1,364 @@
+use config::QueueOverflow;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Twitch chat commands that moderate the channel. Messages starting with one of these are always
+// sent before anything else that's waiting.
+const MOD_COMMANDS: &[&str] = &[
+    "/ban", "/unban", "/timeout", "/untimeout", "/delete", "/clear", "/slow", "/slowoff",
+    "/followers", "/followersoff", "/subscribers", "/subscribersoff", "/emoteonly",
+    "/emoteonlyoff", "/r9kbeta", "/r9kbetaoff",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    // Moderation actions are raised to `High`, everything else keeps the priority of the command
+    // that produced it.
+    pub fn of(msg: &str, default: Priority) -> Priority {
+        let word = msg.split_whitespace().next().unwrap_or("");
+        if MOD_COMMANDS.contains(&word) {
+            Priority::High
+        } else {
+            default
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+const PRIORITIES: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+// Twitch ignores this character when displaying a message, but not when checking whether a
+// message is a duplicate of the last one.
+const DUPLICATE_MARKER: &str = " \u{E0000}";
+
+// Give up on a message after Twitch has rejected it this many times
+const MAX_RETRIES: u8 = 2;
+
+struct Sent {
+    priority: Priority,
+    msg: String,
+    retries: u8,
+}
+
+// How many messages may be sent to a channel within a sliding window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateBudget {
+    pub limit: usize,
+    pub window: Duration,
+}
+
+impl RateBudget {
+    pub fn new(limit: usize, secs: u64) -> Self {
+        Self {
+            limit,
+            window: Duration::from_secs(secs),
+        }
+    }
+}
+
+// Outbound messages for a single channel, waiting to be sent within Twitch's rate limit.
+pub struct SendQueue {
+    queues: [VecDeque<String>; 3],
+    capacity: usize,
+    overflow: QueueOverflow,
+    sent: VecDeque<Instant>,
+    budget: Option<RateBudget>,
+    last: Option<Sent>,
+    retry: Option<Sent>,
+    held_until: Option<Instant>,
+    suspended: bool,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, overflow: QueueOverflow) -> Self {
+        Self {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            capacity,
+            overflow,
+            sent: VecDeque::new(),
+            // Assume we're a regular user until USERSTATE says otherwise
+            budget: Some(RateBudget::new(20, 30)),
+            last: None,
+            retry: None,
+            held_until: None,
+            suspended: false,
+        }
+    }
+
+    pub fn configure(&mut self, capacity: usize, overflow: QueueOverflow) {
+        self.capacity = capacity;
+        self.overflow = overflow;
+    }
+
+    // Change the rate limit. With no budget the bot can't speak in the channel at all, so
+    // messages are held until it can.
+    pub fn set_budget(&mut self, budget: Option<RateBudget>) {
+        self.budget = budget;
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    // Queue a message. If the backlog is full the overflow policy decides what is lost, and the
+    // dropped message (if any) is returned so it can be logged.
+    pub fn push(&mut self, priority: Priority, msg: String) -> Option<String> {
+        if self.len() >= self.capacity {
+            // `drop_oldest` may lose a message of equal priority, `drop_lowest` only makes room by
+            // evicting something less important. Neither will make room for a low priority message
+            // by dropping a more important one.
+            let (levels, oldest) = match self.overflow {
+                QueueOverflow::DropNewest => return Some(msg),
+                QueueOverflow::DropOldest => (Priority::Low.index() - priority.index() + 1, true),
+                QueueOverflow::DropLowest => (Priority::Low.index() - priority.index(), false),
+            };
+            let dropped = self.queues
+                .iter_mut()
+                .rev()
+                .take(levels)
+                .find(|q| !q.is_empty())
+                .and_then(|q| if oldest { q.pop_front() } else { q.pop_back() });
+            if dropped.is_none() {
+                return Some(msg);
+            }
+            self.queues[priority.index()].push_back(msg);
+            return dropped;
+        }
+        self.queues[priority.index()].push_back(msg);
+        None
+    }
+
+    // Take the next message if one may be sent right now. Sends are spaced evenly across the
+    // window rather than bursting the whole budget at once.
+    pub fn pop(&mut self) -> Option<String> {
+        let budget = self.budget?;
+        let now = Instant::now();
+        if self.suspended {
+            return None;
+        }
+        if let Some(until) = self.held_until {
+            if now < until {
+                return None;
+            }
+            self.held_until = None;
+        }
+        while let Some(&inst) = self.sent.front() {
+            if now.duration_since(inst) >= budget.window {
+                self.sent.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.sent.len() >= budget.limit {
+            return None;
+        }
+        if let Some(&last) = self.sent.back() {
+            if now.duration_since(last) < budget.window / budget.limit as u32 {
+                return None;
+            }
+        }
+
+        let next = self.retry.take().or_else(|| {
+            let queues = &mut self.queues;
+            PRIORITIES.iter().find_map(|&priority| {
+                queues[priority.index()].pop_front().map(|msg| Sent { priority, msg, retries: 0 })
+            })
+        });
+        next.map(|sent| {
+            self.sent.push_back(now);
+            let msg = sent.msg.clone();
+            self.last = Some(sent);
+            msg
+        })
+    }
+
+    // The most recently sent message. Twitch doesn't say which message a NOTICE is about, but
+    // sends are spaced far enough apart that it's almost always this one.
+    pub fn last_sent(&self) -> Option<&str> {
+        self.last.as_ref().map(|sent| sent.msg.as_str())
+    }
+
+    // Send the last message again before anything else, optionally changed just enough to get
+    // past the duplicate message check. Returns the message to be resent, or `None` if it has
+    // already been retried too many times.
+    pub fn retry_last(&mut self, vary: bool) -> Option<String> {
+        let mut sent = self.last.take()?;
+        if sent.retries >= MAX_RETRIES {
+            return None;
+        }
+        if vary {
+            sent.msg = if sent.msg.ends_with(DUPLICATE_MARKER) {
+                sent.msg[..sent.msg.len() - DUPLICATE_MARKER.len()].to_string()
+            } else {
+                sent.msg + DUPLICATE_MARKER
+            };
+        }
+        sent.retries += 1;
+        let msg = sent.msg.clone();
+        self.retry = Some(sent);
+        Some(msg)
+    }
+
+    // Send nothing until `duration` has passed
+    pub fn hold(&mut self, duration: Duration) {
+        self.held_until = Some(Instant::now() + duration);
+    }
+
+    // Stop sending and throw away the backlog, until `resume` is called
+    pub fn suspend(&mut self) {
+        self.suspended = true;
+        self.clear();
+    }
+
+    pub fn resume(&mut self) {
+        self.suspended = false;
+        self.held_until = None;
+    }
+
+    // Nothing is left that will ever be sent
+    pub fn is_drained(&self) -> bool {
+        self.suspended || (self.retry.is_none() && self.len() == 0)
+    }
+
+    pub fn clear(&mut self) {
+        for queue in &mut self.queues {
+            queue.clear();
+        }
+        self.retry = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A queue that sends as fast as it's asked to
+    fn queue(capacity: usize, overflow: QueueOverflow) -> SendQueue {
+        let mut queue = SendQueue::new(capacity, overflow);
+        queue.set_budget(Some(RateBudget::new(100, 0)));
+        queue
+    }
+
+    fn push(queue: &mut SendQueue, priority: Priority, msg: &str) -> Option<String> {
+        queue.push(priority, msg.to_string())
+    }
+
+    fn drain(queue: &mut SendQueue) -> Vec<String> {
+        let mut sent = Vec::new();
+        while let Some(msg) = queue.pop() {
+            sent.push(msg);
+        }
+        sent
+    }
+
+    #[test]
+    fn highest_priority_first() {
+        let mut q = queue(10, QueueOverflow::DropNewest);
+        assert_eq!(push(&mut q, Priority::Low, "l1"), None);
+        assert_eq!(push(&mut q, Priority::Normal, "n1"), None);
+        assert_eq!(push(&mut q, Priority::High, "h1"), None);
+        assert_eq!(push(&mut q, Priority::Normal, "n2"), None);
+        assert_eq!(drain(&mut q), vec!["h1", "n1", "n2", "l1"]);
+        assert!(q.is_drained());
+    }
+
+    #[test]
+    fn mod_commands_go_first() {
+        assert_eq!(Priority::of("/ban someone", Priority::Low), Priority::High);
+        assert_eq!(Priority::of("/clear", Priority::Normal), Priority::High);
+        assert_eq!(Priority::of("/banana", Priority::Low), Priority::Low);
+        assert_eq!(Priority::of("say /ban", Priority::Normal), Priority::Normal);
+        assert_eq!(Priority::of("", Priority::Normal), Priority::Normal);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let mut q = queue(2, QueueOverflow::DropNewest);
+        push(&mut q, Priority::Low, "l1");
+        push(&mut q, Priority::Low, "l2");
+        assert_eq!(push(&mut q, Priority::High, "h1"), Some(String::from("h1")));
+        assert_eq!(push(&mut q, Priority::Low, "l3"), Some(String::from("l3")));
+        assert_eq!(drain(&mut q), vec!["l1", "l2"]);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut q = queue(2, QueueOverflow::DropOldest);
+        push(&mut q, Priority::Normal, "n1");
+        push(&mut q, Priority::Normal, "n2");
+        // The oldest of the same priority makes room
+        assert_eq!(push(&mut q, Priority::Normal, "n3"), Some(String::from("n1")));
+        // But nothing more important does
+        assert_eq!(push(&mut q, Priority::Low, "l1"), Some(String::from("l1")));
+        assert_eq!(drain(&mut q), vec!["n2", "n3"]);
+
+        let mut q = queue(3, QueueOverflow::DropOldest);
+        push(&mut q, Priority::Low, "l1");
+        push(&mut q, Priority::Low, "l2");
+        push(&mut q, Priority::Normal, "n1");
+        // The least important go first
+        assert_eq!(push(&mut q, Priority::High, "h1"), Some(String::from("l1")));
+        assert_eq!(drain(&mut q), vec!["h1", "n1", "l2"]);
+    }
+
+    #[test]
+    fn drop_lowest() {
+        let mut q = queue(2, QueueOverflow::DropLowest);
+        push(&mut q, Priority::Low, "l1");
+        push(&mut q, Priority::Low, "l2");
+        // The newest less important message makes room
+        assert_eq!(push(&mut q, Priority::Normal, "n1"), Some(String::from("l2")));
+        // Nothing of the same priority does
+        assert_eq!(push(&mut q, Priority::Low, "l3"), Some(String::from("l3")));
+        assert_eq!(push(&mut q, Priority::High, "h1"), Some(String::from("l1")));
+        assert_eq!(push(&mut q, Priority::Normal, "n2"), Some(String::from("n2")));
+        assert_eq!(push(&mut q, Priority::High, "h2"), Some(String::from("n1")));
+        assert_eq!(push(&mut q, Priority::High, "h3"), Some(String::from("h3")));
+        assert_eq!(drain(&mut q), vec!["h1", "h2"]);
+    }
+
+    #[test]
+    fn reconfigured() {
+        let mut q = queue(1, QueueOverflow::DropNewest);
+        push(&mut q, Priority::Normal, "n1");
+        q.configure(1, QueueOverflow::DropOldest);
+        assert_eq!(push(&mut q, Priority::Normal, "n2"), Some(String::from("n1")));
+        q.configure(2, QueueOverflow::DropOldest);
+        assert_eq!(push(&mut q, Priority::Normal, "n3"), None);
+        assert_eq!(drain(&mut q), vec!["n2", "n3"]);
+    }
+
+    #[test]
+    fn held_without_budget() {
+        let mut q = queue(10, QueueOverflow::DropNewest);
+        push(&mut q, Priority::Normal, "n1");
+        q.set_budget(None);
+        assert_eq!(q.pop(), None);
+        assert!(!q.is_drained());
+        q.set_budget(Some(RateBudget::new(100, 0)));
+        assert_eq!(drain(&mut q), vec!["n1"]);
+    }
+
+    #[test]
+    fn rate_limited() {
+        let mut q = queue(10, QueueOverflow::DropNewest);
+        q.set_budget(Some(RateBudget::new(20, 30)));
+        push(&mut q, Priority::Normal, "n1");
+        push(&mut q, Priority::Normal, "n2");
+        // Sends are spaced out over the window
+        assert_eq!(drain(&mut q), vec!["n1"]);
+    }
+}
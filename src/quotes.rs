@@ -0,0 +1,186 @@
+use rusqlite::{self, Connection, Row};
+use rusqlite::types::ToSql;
+
+// Columns read by `Quote::from_row`, in order
+const COLUMNS: &str = "id, quote, author, added_by, date(added_at, 'unixepoch'), category";
+
+pub struct Quote {
+    pub id: u32,
+    pub text: String,
+    // Who is quoted
+    pub author: Option<String>,
+    pub added_by: Option<String>,
+    // YYYY-MM-DD
+    pub date: Option<String>,
+    // The game or category being streamed at the time
+    pub category: Option<String>,
+}
+
+impl Quote {
+    fn from_row(row: &Row) -> Quote {
+        Quote {
+            id: row.get(0),
+            text: row.get(1),
+            author: row.get(2),
+            added_by: row.get(3),
+            date: row.get(4),
+            category: row.get(5),
+        }
+    }
+
+    // Fill in a template like "[{id}] {quote} - {author}, {year}". Fields that weren't recorded are
+    // left blank. It's done in one pass, so placeholders typed into a quote or its author are left
+    // alone.
+    pub fn format(&self, template: &str) -> String {
+        let mut out = String::with_capacity(template.len() + self.text.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            let field = rest[start + 1..].find('}').map(|end| &rest[start + 1..start + 1 + end]);
+            let value = match field {
+                Some("id") => Some(self.id.to_string()),
+                Some("quote") => Some(self.text.clone()),
+                Some("author") => Some(self.author.clone().unwrap_or_default()),
+                Some("added_by") => Some(self.added_by.clone().unwrap_or_default()),
+                Some("date") => Some(self.date.clone().unwrap_or_default()),
+                Some("year") => Some(self.date.as_ref().and_then(|date| date.get(..4)).unwrap_or("").to_string()),
+                Some("category") => Some(self.category.clone().unwrap_or_default()),
+                _ => None,
+            };
+            match (field, value) {
+                (Some(field), Some(value)) => {
+                    out.push_str(&value);
+                    rest = &rest[start + field.len() + 2..];
+                }
+                _ => {
+                    out.push('{');
+                    rest = &rest[start + 1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+pub fn get(db: &Connection, id: u32) -> rusqlite::Result<Quote> {
+    let sql = format!("SELECT {} FROM quote WHERE id=?1 AND deleted_at IS NULL", COLUMNS);
+    db.query_row(&sql, &[&id], Quote::from_row)
+}
+
+pub fn random(db: &Connection) -> rusqlite::Result<Quote> {
+    let sql = format!("SELECT {} FROM quote WHERE deleted_at IS NULL ORDER BY RANDOM() LIMIT 1", COLUMNS);
+    db.query_row(&sql, &[], Quote::from_row)
+}
+
+// What to look for. Terms are matched anywhere in the quote, its author or category; the author
+// has to match exactly, ignoring case.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub terms: Option<String>,
+    pub author: Option<String>,
+}
+
+impl Filter {
+    // SQL for the ids of matching quotes, with the parameters it takes
+    fn sql(&self) -> (String, Vec<String>) {
+        let mut conds = vec![String::from("deleted_at IS NULL")];
+        let mut params = Vec::new();
+        if let Some(terms) = &self.terms {
+            // Quote each word so punctuation isn't taken as FTS syntax
+            let query: Vec<String> = terms
+                .split_whitespace()
+                .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+                .collect();
+            params.push(query.join(" "));
+            conds.push(format!("id IN (SELECT rowid FROM quote_fts WHERE quote_fts MATCH ?{})", params.len()));
+        }
+        if let Some(author) = &self.author {
+            params.push(author.clone());
+            conds.push(format!("author = ?{} COLLATE NOCASE", params.len()));
+        }
+        (conds.join(" AND "), params)
+    }
+}
+
+// A search being paged through with !quote next
+#[derive(Clone, Debug)]
+pub struct Search {
+    pub filter: Filter,
+    pub offset: u32,
+    pub total: u32,
+}
+
+pub fn count(db: &Connection, filter: &Filter) -> rusqlite::Result<u32> {
+    let (cond, params) = filter.sql();
+    let params: Vec<&dyn ToSql> = params.iter().map(|p| p as &dyn ToSql).collect();
+    let sql = format!("SELECT COUNT(*) FROM quote WHERE {}", cond);
+    db.query_row(&sql, &params, |row| row.get::<_, i64>(0) as u32)
+}
+
+// The match at `offset`, oldest first
+pub fn find(db: &Connection, filter: &Filter, offset: u32) -> rusqlite::Result<Quote> {
+    let (cond, params) = filter.sql();
+    let mut params: Vec<&dyn ToSql> = params.iter().map(|p| p as &dyn ToSql).collect();
+    params.push(&offset);
+    let sql = format!(
+        "SELECT {} FROM quote WHERE {} ORDER BY id LIMIT 1 OFFSET ?{}",
+        COLUMNS,
+        cond,
+        params.len()
+    );
+    db.query_row(&sql, &params, Quote::from_row)
+}
+
+// Add a quote, returning its id. It's dated now unless `added_at` is given.
+pub fn add(
+    db: &Connection,
+    text: &str,
+    author: Option<&str>,
+    category: Option<&str>,
+    added_by: &str,
+    added_at: Option<i64>,
+) -> rusqlite::Result<u32> {
+    db.execute(
+        "INSERT INTO quote (quote, author, category, added_by, added_at)
+         VALUES (?1, ?2, ?3, ?4, COALESCE(?5, strftime('%s', 'now')))",
+        &[&text, &author, &category, &added_by, &added_at],
+    )?;
+    Ok(db.last_insert_rowid() as u32)
+}
+
+// Replace the text of a quote, keeping everything else. False if there's no such quote.
+pub fn edit(db: &Connection, id: u32, text: &str) -> rusqlite::Result<bool> {
+    let n = db.execute(
+        "UPDATE quote SET quote=?1 WHERE id=?2 AND deleted_at IS NULL",
+        &[&text, &id],
+    )?;
+    Ok(n > 0)
+}
+
+// Hide a quote until it's restored or purged
+pub fn delete(db: &Connection, id: u32, deleted_by: &str) -> rusqlite::Result<bool> {
+    let n = db.execute(
+        "UPDATE quote SET deleted_at=strftime('%s', 'now'), deleted_by=?1
+         WHERE id=?2 AND deleted_at IS NULL",
+        &[&deleted_by, &id],
+    )?;
+    Ok(n > 0)
+}
+
+pub fn restore(db: &Connection, id: u32) -> rusqlite::Result<bool> {
+    let n = db.execute(
+        "UPDATE quote SET deleted_at=NULL, deleted_by=NULL WHERE id=?1 AND deleted_at IS NOT NULL",
+        &[&id],
+    )?;
+    Ok(n > 0)
+}
+
+// Remove deleted quotes for good, either one or all of them. Returns how many went.
+pub fn purge(db: &Connection, id: Option<u32>) -> rusqlite::Result<usize> {
+    match id {
+        Some(id) => db.execute("DELETE FROM quote WHERE id=?1 AND deleted_at IS NOT NULL", &[&id]),
+        None => db.execute("DELETE FROM quote WHERE deleted_at IS NOT NULL", &[]),
+    }
+    .map(|n| n as usize)
+}
@@ -0,0 +1,118 @@
+use rusqlite::{self, Connection};
+
+// Channel-defined groups of users, e.g. `editors`. An alias or built-in command can name one, and its
+// members may use it on top of whoever its permissions already allow.
+//
+// Members are kept by user-id. Someone added by a name that hasn't been seen in chat yet is kept by
+// that name until they're next seen, see `users::seen`.
+
+// Matches the member `?2` (their id, if known) with login `?3`
+const MEMBER: &str = "(role_member.user_id=?2 OR (role_member.user_id IS NULL AND role_member.login=?3))";
+
+// Lowercase letters, digits, `-` and `_`
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+// Add a user to a role, making the role if it's new. False if they already had it.
+pub fn grant(
+    db: &Connection,
+    role: &str,
+    user_id: Option<&str>,
+    login: &str,
+    added_by: &str,
+) -> rusqlite::Result<bool> {
+    if has(db, role, user_id, login)? {
+        return Ok(false);
+    }
+    db.execute("INSERT OR IGNORE INTO role (name) VALUES (?1)", &[&role])?;
+    db.execute(
+        "INSERT INTO role_member (role_id, user_id, login, added_by, added_at)
+         SELECT id, ?2, ?3, ?4, strftime('%s', 'now') FROM role WHERE name=?1",
+        &[&role, &user_id, &login, &added_by],
+    )?;
+    Ok(true)
+}
+
+// False if they didn't have it
+pub fn revoke(db: &Connection, role: &str, user_id: Option<&str>, login: &str) -> rusqlite::Result<bool> {
+    let sql = format!(
+        "DELETE FROM role_member WHERE role_id=(SELECT id FROM role WHERE name=?1) AND {}",
+        MEMBER
+    );
+    let n = db.execute(&sql, &[&role, &user_id, &login])?;
+    Ok(n > 0)
+}
+
+// Remove a role and everyone in it. False if there's no such role.
+pub fn remove(db: &Connection, role: &str) -> rusqlite::Result<bool> {
+    db.execute(
+        "DELETE FROM role_member WHERE role_id=(SELECT id FROM role WHERE name=?1)",
+        &[&role],
+    )?;
+    let n = db.execute("DELETE FROM role WHERE name=?1", &[&role])?;
+    Ok(n > 0)
+}
+
+pub fn has(db: &Connection, role: &str, user_id: Option<&str>, login: &str) -> rusqlite::Result<bool> {
+    let sql = format!(
+        "SELECT COUNT(*) FROM role_member JOIN role ON role.id = role_member.role_id
+         WHERE role.name=?1 AND {}",
+        MEMBER
+    );
+    let n: i64 = db.query_row(&sql, &[&role, &user_id, &login], |row| row.get(0))?;
+    Ok(n > 0)
+}
+
+// Every role, with how many users are in it
+pub fn list(db: &Connection) -> rusqlite::Result<Vec<(String, u32)>> {
+    let mut stmt = db.prepare(
+        "SELECT role.name, COUNT(role_member.role_id) FROM role
+         LEFT JOIN role_member ON role.id = role_member.role_id
+         GROUP BY role.id ORDER BY role.name",
+    )?;
+    let rows = stmt.query_map(&[], |row| (row.get(0), row.get::<_, i64>(1) as u32))?;
+    rows.collect()
+}
+
+// The role a built-in command was opened up to, if any
+pub fn command_role(db: &Connection, command: &str) -> rusqlite::Result<Option<String>> {
+    let mut stmt = db.prepare("SELECT role FROM command_role WHERE command=?1")?;
+    let mut rows = stmt.query(&[&command])?;
+    match rows.next() {
+        Some(row) => Ok(Some(row?.get(0))),
+        None => Ok(None),
+    }
+}
+
+// Open a built-in command up to a role, or with None, close it again
+pub fn set_command_role(db: &Connection, command: &str, role: Option<&str>) -> rusqlite::Result<()> {
+    match role {
+        Some(role) => db.execute(
+            "INSERT OR REPLACE INTO command_role (command, role) VALUES (?1, ?2)",
+            &[&command, &role],
+        )?,
+        None => db.execute("DELETE FROM command_role WHERE command=?1", &[&command])?,
+    };
+    Ok(())
+}
+
+pub fn exists(db: &Connection, role: &str) -> rusqlite::Result<bool> {
+    let n: i64 = db.query_row("SELECT COUNT(*) FROM role WHERE name=?1", &[&role], |row| row.get(0))?;
+    Ok(n > 0)
+}
+
+// None if there's no such role
+pub fn members(db: &Connection, role: &str) -> rusqlite::Result<Option<Vec<String>>> {
+    if !exists(db, role)? {
+        return Ok(None);
+    }
+    let mut stmt = db.prepare(
+        "SELECT login FROM role_member WHERE role_id=(SELECT id FROM role WHERE name=?1) ORDER BY login",
+    )?;
+    let rows = stmt.query_map(&[&role], |row| row.get(0))?;
+    rows.collect::<rusqlite::Result<_>>().map(Some)
+}
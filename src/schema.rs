@@ -0,0 +1,401 @@
+use rusqlite::{self, Connection};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// A step in the history of the channel database. Each one takes the database from the version
+// before it to its own, and runs in a transaction along with the bump of `schema_version`.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+// In order, and never edited once released. Add a new migration instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create quote and alias tables",
+        apply: create_tables,
+    },
+    Migration {
+        version: 2,
+        description: "add per-alias cooldowns",
+        apply: alias_cooldowns,
+    },
+    Migration {
+        version: 3,
+        description: "add quote author, category and who added it when",
+        apply: quote_metadata,
+    },
+    Migration {
+        version: 4,
+        description: "add full-text quote search",
+        apply: quote_search,
+    },
+    Migration {
+        version: 5,
+        description: "soft delete quotes, and never reuse their ids",
+        apply: quote_soft_delete,
+    },
+    Migration {
+        version: 6,
+        description: "widen alias permissions past 8 bits",
+        apply: alias_wide_auth,
+    },
+    Migration {
+        version: 7,
+        description: "add custom roles",
+        apply: roles,
+    },
+    Migration {
+        version: 8,
+        description: "record Twitch user-ids, and keep role members by them",
+        apply: user_ids,
+    },
+    Migration {
+        version: 9,
+        description: "add custom commands",
+        apply: custom_commands,
+    },
+    Migration {
+        version: 10,
+        description: "add alias argument checks, and escape `$` in alias commands",
+        apply: alias_arg_rules,
+    },
+    Migration {
+        version: 11,
+        description: "escape `;` in alias commands, which now separates them",
+        apply: alias_chains,
+    },
+    Migration {
+        version: 12,
+        description: "record who added each alias and when",
+        apply: alias_added,
+    },
+    Migration {
+        version: 13,
+        description: "let roles use built-in commands",
+        apply: command_roles,
+    },
+];
+
+fn create_tables(db: &Connection) -> rusqlite::Result<()> {
+    // Databases from before migrations existed already have these
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS quote (
+            id       INTEGER PRIMARY KEY,
+            quote    TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS alias (
+            id         INTEGER PRIMARY KEY,
+            auth       INTEGER NOT NULL,
+            alias      TEXT NOT NULL,
+            command    TEXT NOT NULL
+        );",
+    )
+}
+
+fn alias_cooldowns(db: &Connection) -> rusqlite::Result<()> {
+    for column in &["bucket_count", "bucket_secs", "user_bucket_count", "user_bucket_secs"] {
+        add_column(db, "alias", column, "INTEGER")?;
+    }
+    Ok(())
+}
+
+fn quote_metadata(db: &Connection) -> rusqlite::Result<()> {
+    add_column(db, "quote", "author", "TEXT")?;
+    add_column(db, "quote", "category", "TEXT")?;
+    add_column(db, "quote", "added_by", "TEXT")?;
+    add_column(db, "quote", "added_at", "INTEGER")
+}
+
+// An index over the quote table, kept in step with it by triggers
+fn quote_search(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE VIRTUAL TABLE quote_fts USING fts5(
+            quote, author, category, content='quote', content_rowid='id'
+        );
+        CREATE TRIGGER quote_fts_insert AFTER INSERT ON quote BEGIN
+            INSERT INTO quote_fts (rowid, quote, author, category)
+            VALUES (new.id, new.quote, new.author, new.category);
+        END;
+        CREATE TRIGGER quote_fts_delete AFTER DELETE ON quote BEGIN
+            INSERT INTO quote_fts (quote_fts, rowid, quote, author, category)
+            VALUES ('delete', old.id, old.quote, old.author, old.category);
+        END;
+        CREATE TRIGGER quote_fts_update AFTER UPDATE ON quote BEGIN
+            INSERT INTO quote_fts (quote_fts, rowid, quote, author, category)
+            VALUES ('delete', old.id, old.quote, old.author, old.category);
+            INSERT INTO quote_fts (rowid, quote, author, category)
+            VALUES (new.id, new.quote, new.author, new.category);
+        END;
+        INSERT INTO quote_fts (quote_fts) VALUES ('rebuild');",
+    )
+}
+
+// Rebuilt with AUTOINCREMENT so that purging the newest quote doesn't free its id. Dropping the old
+// table drops its triggers, so they're made again.
+fn quote_soft_delete(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE quote_new (
+            id         INTEGER PRIMARY KEY AUTOINCREMENT,
+            quote      TEXT NOT NULL,
+            author     TEXT,
+            category   TEXT,
+            added_by   TEXT,
+            added_at   INTEGER,
+            deleted_at INTEGER,
+            deleted_by TEXT
+        );
+        INSERT INTO quote_new (id, quote, author, category, added_by, added_at)
+            SELECT id, quote, author, category, added_by, added_at FROM quote;
+        DROP TABLE quote;
+        ALTER TABLE quote_new RENAME TO quote;
+        CREATE TRIGGER quote_fts_insert AFTER INSERT ON quote BEGIN
+            INSERT INTO quote_fts (rowid, quote, author, category)
+            VALUES (new.id, new.quote, new.author, new.category);
+        END;
+        CREATE TRIGGER quote_fts_delete AFTER DELETE ON quote BEGIN
+            INSERT INTO quote_fts (quote_fts, rowid, quote, author, category)
+            VALUES ('delete', old.id, old.quote, old.author, old.category);
+        END;
+        CREATE TRIGGER quote_fts_update AFTER UPDATE ON quote BEGIN
+            INSERT INTO quote_fts (quote_fts, rowid, quote, author, category)
+            VALUES ('delete', old.id, old.quote, old.author, old.category);
+            INSERT INTO quote_fts (rowid, quote, author, category)
+            VALUES (new.id, new.quote, new.author, new.category);
+        END;",
+    )
+}
+
+// SQLite integers are already wide enough and the old bits keep their values, so no data changes.
+// The version bump is what matters: older builds read `auth` as a byte and would fail on aliases
+// that use the new roles, so this keeps them from opening the database at all.
+fn alias_wide_auth(_db: &Connection) -> rusqlite::Result<()> {
+    Ok(())
+}
+
+fn roles(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE role (
+            id       INTEGER PRIMARY KEY,
+            name     TEXT NOT NULL UNIQUE
+        );
+        CREATE TABLE role_member (
+            role_id  INTEGER NOT NULL,
+            user     TEXT NOT NULL,
+            added_by TEXT,
+            added_at INTEGER,
+            PRIMARY KEY (role_id, user)
+        );",
+    )?;
+    add_column(db, "alias", "role", "TEXT")
+}
+
+// Existing members only have a login, and are pinned to an id when they're next seen in chat
+fn user_ids(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE twitch_user (
+            id           TEXT PRIMARY KEY,
+            login        TEXT NOT NULL,
+            display_name TEXT,
+            first_seen   INTEGER NOT NULL,
+            updated_at   INTEGER NOT NULL
+        );
+        CREATE INDEX twitch_user_login ON twitch_user (login);
+        CREATE TABLE role_member_new (
+            role_id  INTEGER NOT NULL,
+            user_id  TEXT,
+            login    TEXT NOT NULL,
+            added_by TEXT,
+            added_at INTEGER,
+            UNIQUE (role_id, user_id)
+        );
+        INSERT INTO role_member_new (role_id, login, added_by, added_at)
+            SELECT role_id, user, added_by, added_at FROM role_member;
+        DROP TABLE role_member;
+        ALTER TABLE role_member_new RENAME TO role_member;",
+    )
+}
+
+fn custom_commands(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE custom_cmd (
+            id       INTEGER PRIMARY KEY,
+            name     TEXT NOT NULL UNIQUE,
+            response TEXT NOT NULL,
+            auth     INTEGER NOT NULL,
+            count    INTEGER NOT NULL DEFAULT 0,
+            added_by TEXT,
+            added_at INTEGER
+        );",
+    )
+}
+
+// `$1` and `${` start placeholders now and `$$` is a literal `$`, so existing aliases keep saying
+// what they said
+fn alias_arg_rules(db: &Connection) -> rusqlite::Result<()> {
+    add_column(db, "alias", "min_args", "INTEGER")?;
+    add_column(db, "alias", "max_args", "INTEGER")?;
+    // JSON, e.g. {"1": "\\d+"}
+    add_column(db, "alias", "arg_patterns", "TEXT")?;
+    db.execute("UPDATE alias SET command = replace(command, '$', '$$')", &[])?;
+    Ok(())
+}
+
+// `;;` is a literal `;` now, so existing aliases keep saying what they said
+fn alias_chains(db: &Connection) -> rusqlite::Result<()> {
+    db.execute("UPDATE alias SET command = replace(command, ';', ';;')", &[])?;
+    Ok(())
+}
+
+fn alias_added(db: &Connection) -> rusqlite::Result<()> {
+    add_column(db, "alias", "added_by", "TEXT")?;
+    add_column(db, "alias", "added_at", "INTEGER")
+}
+
+// Roles are kept by name, as for aliases
+fn command_roles(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE command_role (
+            command TEXT PRIMARY KEY,
+            role    TEXT NOT NULL
+        );",
+    )
+}
+
+// Add a column unless an older build already added it by hand
+fn add_column(db: &Connection, table: &str, column: &str, decl: &str) -> rusqlite::Result<()> {
+    let exists = {
+        let mut stmt = db.prepare(&format!("PRAGMA table_info({})", table))?;
+        let names = stmt.query_map(&[], |row| row.get::<_, String>(1))?;
+        let mut exists = false;
+        for name in names {
+            if name? == column {
+                exists = true;
+            }
+        }
+        exists
+    };
+    if !exists {
+        db.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl), &[])?;
+    }
+    Ok(())
+}
+
+// The newest schema this build knows about
+pub fn latest() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+// The schema the database is at, 0 if it has never been migrated
+pub fn version(db: &Connection) -> rusqlite::Result<u32> {
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version     INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at  INTEGER NOT NULL
+        )",
+        &[],
+    )?;
+    let version: Option<i64> = db.query_row("SELECT MAX(version) FROM schema_version", &[], |row| row.get(0))?;
+    Ok(version.unwrap_or(0) as u32)
+}
+
+// Open a channel database, creating or upgrading it as needed
+pub fn open(path: &Path) -> Result<Connection, MigrationError> {
+    let mut db = Connection::open(path)?;
+    migrate(&mut db, path)?;
+    Ok(db)
+}
+
+// Bring the database at `path` up to date, backing it up first if there's anything to do
+pub fn migrate(db: &mut Connection, path: &Path) -> Result<(), MigrationError> {
+    let current = version(db)?;
+    if current > latest() {
+        return Err(MigrationError::TooNew {
+            found: current,
+            known: latest(),
+        });
+    }
+    if current == latest() {
+        return Ok(());
+    }
+
+    // Progress goes to stderr, so it isn't mixed into an export written to stdout.
+    // Nothing is worth backing up in a database that was only just created.
+    let backup = backup_path(path, current);
+    if current > 0 || has_tables(db)? {
+        fs::copy(path, &backup).map_err(|e| MigrationError::Backup(backup.clone(), e))?;
+        eprintln!("Backed up {} to {}", path.display(), backup.display());
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = db.transaction()?;
+        (migration.apply)(&tx).map_err(|e| MigrationError::Failed(migration.version, e))?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at)
+             VALUES (?1, ?2, strftime('%s', 'now'))",
+            &[&migration.version, &migration.description],
+        )?;
+        tx.commit()?;
+        eprintln!(
+            "Migrated {} to schema version {}: {}",
+            path.display(),
+            migration.version,
+            migration.description
+        );
+    }
+    Ok(())
+}
+
+fn has_tables(db: &Connection) -> rusqlite::Result<bool> {
+    let count: i64 = db.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name != 'schema_version'",
+        &[],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+// e.g. `db.v2.bak` for a database at version 2
+fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = path.file_name().map_or_else(Default::default, |name| name.to_os_string());
+    name.push(format!(".v{}.bak", version));
+    path.with_file_name(name)
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    // The database was upgraded by a newer build
+    TooNew { found: u32, known: u32 },
+    Backup(PathBuf, io::Error),
+    Failed(u32, rusqlite::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(e: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(e)
+    }
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::TooNew { found, known } => write!(
+                f,
+                "database is at schema version {}, but this build only knows up to version {}; \
+                 upgrade zbot or restore a backup",
+                found, known
+            ),
+            MigrationError::Backup(path, e) => write!(f, "unable to back up to `{}`: {}", path.display(), e),
+            MigrationError::Failed(version, e) => {
+                write!(f, "migration to schema version {} failed and was rolled back: {}", version, e)
+            }
+            MigrationError::Sqlite(e) => write!(f, "{}", e),
+        }
+    }
+}
@@ -1,26 +1,127 @@
+use config::Channel;
+use quotes::Search;
 use rusqlite::Connection;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::mpsc::Sender;
+use supervisor::Event;
+use twitch::{RoomState, UserState};
+
+// How many chat messages each channel remembers for !grab
+const RECENT_CHAT: usize = 100;
 
 pub struct MainState {
-    pub shutdown: bool,
+    // Asks the supervisor to reload or shut down
+    pub events: Sender<Event>,
+    pub health: HashMap<String, WorkerHealth>,
+    // Messages from the supervisor for the owners, whispered by whichever channel sees them first
+    pub notices: Vec<String>,
 }
 
 impl MainState {
-    pub fn new() -> Arc<Mutex<Self>> {
-        let state = MainState { shutdown: false };
+    pub fn new(events: &Sender<Event>) -> Arc<Mutex<Self>> {
+        let state = MainState {
+            events: events.clone(),
+            health: HashMap::new(),
+            notices: Vec::new(),
+        };
         Arc::new(Mutex::new(state))
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Running,
+    Restarting,
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+pub struct WorkerHealth {
+    pub status: Status,
+    pub restarts: u32,
+    pub last_crash: Option<String>,
+}
+
 pub struct ThreadState {
     pub main: Arc<Mutex<MainState>>,
+    // The channel's settings, kept up to date across reloads
+    pub cfg: Channel,
     pub db: Option<Connection>,
     pub poll_id: Option<u32>,
+    pub quote_search: Option<Search>,
+    pub recent: RecentChat,
+    // The login recorded in the database for each user-id seen so far, to skip rewriting it
+    pub known_users: HashMap<String, String>,
+    pub room: RoomState,
+    pub bot: UserState,
 }
 
 impl ThreadState {
     pub fn new(main: Arc<Mutex<MainState>>) -> Arc<Mutex<ThreadState>> {
-        let state = ThreadState { main, db: None, poll_id: None };
+        let state = ThreadState {
+            main,
+            cfg: Channel::default(),
+            db: None,
+            poll_id: None,
+            quote_search: None,
+            recent: RecentChat::default(),
+            known_users: HashMap::new(),
+            room: RoomState::default(),
+            bot: UserState::default(),
+        };
         Arc::new(Mutex::new(state))
     }
+
+    // Carry the state of a crashed thread over to its replacement. The old mutex is likely
+    // poisoned, and the database is reopened by the new thread.
+    pub fn recover(old: &Arc<Mutex<ThreadState>>) -> Arc<Mutex<ThreadState>> {
+        let old = old.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = ThreadState {
+            main: Arc::clone(&old.main),
+            cfg: old.cfg.clone(),
+            db: None,
+            poll_id: old.poll_id,
+            quote_search: old.quote_search.clone(),
+            recent: old.recent.clone(),
+            known_users: old.known_users.clone(),
+            room: old.room.clone(),
+            bot: old.bot.clone(),
+        };
+        Arc::new(Mutex::new(state))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChatLine {
+    // Login name
+    pub user: String,
+    pub display_name: String,
+    pub text: String,
+    // Unix time
+    pub time: i64,
+}
+
+// The last few messages in a channel, oldest first
+#[derive(Clone, Debug, Default)]
+pub struct RecentChat {
+    lines: VecDeque<ChatLine>,
+}
+
+impl RecentChat {
+    pub fn push(&mut self, line: ChatLine) {
+        if self.lines.len() >= RECENT_CHAT {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    // A user's `n`th most recent message, counting from 1
+    pub fn by_user(&self, user: &str, n: usize) -> Option<&ChatLine> {
+        self.lines
+            .iter()
+            .rev()
+            .filter(|line| line.user.eq_ignore_ascii_case(user))
+            .nth(n.checked_sub(1)?)
+    }
 }
@@ -0,0 +1,423 @@
+use backoff::Backoff;
+use config::{Channel, Config, ConfigPaths};
+use state::{MainState, Status, ThreadState, WorkerHealth};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use twitch::{self, Control, Controls};
+
+// A channel that crashes this many times within the window is given up on
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: u64 = 600;
+
+// How long the channels get to say goodbye before the process exits anyway
+const SHUTDOWN_TIMEOUT: u64 = 15;
+
+// Things the supervisor is told about
+pub enum Event {
+    // A worker's thread ended, with the generation it was started as
+    Exited(String, u64),
+    Reload,
+    Shutdown,
+}
+
+// A thread running one channel, or all of them when they share a connection
+struct Worker {
+    channels: Vec<(Arc<Mutex<ThreadState>>, Channel)>,
+    shared: bool,
+    // Returns Err if its channel can't be run at all
+    handle: Option<JoinHandle<Result<(), String>>>,
+    // Which start of this worker `handle` belongs to, so a parted thread's late exit is ignored
+    generation: u64,
+    crashes: VecDeque<Instant>,
+    backoff: Backoff,
+    restart_at: Option<Instant>,
+    controls: Controls,
+}
+
+// Runs the channels' threads and restarts the ones that die
+pub struct Supervisor {
+    main: Arc<Mutex<MainState>>,
+    paths: ConfigPaths,
+    cfg: Config,
+    workers: HashMap<String, Worker>,
+    events: Receiver<Event>,
+    generation: u64,
+}
+
+impl Supervisor {
+    // `events` receives what is sent through `MainState.events`
+    pub fn new(paths: ConfigPaths, cfg: Config, main: &Arc<Mutex<MainState>>, events: Receiver<Event>) -> Self {
+        let mut supervisor = Self {
+            main: Arc::clone(main),
+            paths,
+            cfg: cfg.clone(),
+            workers: HashMap::new(),
+            events,
+            generation: 0,
+        };
+        let channels = cfg.channels
+            .values()
+            .map(|channel| (ThreadState::new(Arc::clone(main)), channel.clone()));
+        if cfg.shared_connection {
+            supervisor.start(String::from("shared"), channels.collect(), true);
+        } else {
+            for channel in channels {
+                supervisor.start(format!("#{}", channel.1.name), vec![channel], false);
+            }
+        }
+        supervisor
+    }
+
+    fn start(&mut self, name: String, channels: Vec<(Arc<Mutex<ThreadState>>, Channel)>, shared: bool) {
+        let controls = {
+            let worker = self.workers.entry(name.clone()).or_insert_with(|| Worker {
+                channels: Vec::new(),
+                shared,
+                handle: None,
+                generation: 0,
+                crashes: VecDeque::new(),
+                backoff: Backoff::new(Duration::from_secs(1), Duration::from_secs(60)),
+                restart_at: None,
+                controls: Arc::new(Mutex::new(Vec::new())),
+            });
+            // The new thread starts out with the current channels, so anything pending is stale
+            worker.controls.lock().unwrap().clear();
+            Arc::clone(&worker.controls)
+        };
+        self.generation += 1;
+        let generation = self.generation;
+        let handle = self.spawn(&name, generation, &channels, shared, &controls);
+        let worker = self.workers.get_mut(&name).unwrap();
+        worker.channels = channels;
+        worker.handle = Some(handle);
+        worker.generation = generation;
+        worker.restart_at = None;
+
+        let mut main = self.main.lock().unwrap();
+        let health = main.health.entry(name).or_insert_with(|| WorkerHealth {
+            status: Status::Running,
+            restarts: 0,
+            last_crash: None,
+        });
+        health.status = Status::Running;
+    }
+
+    fn spawn(
+        &self,
+        name: &str,
+        generation: u64,
+        channels: &[(Arc<Mutex<ThreadState>>, Channel)],
+        shared: bool,
+        controls: &Controls,
+    ) -> JoinHandle<Result<(), String>> {
+        // Create local copies of variables
+        let user = self.cfg.user.clone();
+        let pass = self.cfg.pass.clone();
+        let owners = self.cfg.owners.clone();
+        let channels = channels.to_vec();
+        let controls = Arc::clone(controls);
+        let guard = ExitGuard {
+            name: name.to_string(),
+            generation,
+            tx: self.main.lock().unwrap().events.clone(),
+        };
+
+        // Spawn thread
+        thread::spawn(move || {
+            let _guard = guard;
+            if shared {
+                twitch::init_shared(&channels, &owners, &user, &pass, &controls)
+            } else {
+                let (t_state, channel) = &channels[0];
+                twitch::init(t_state, channel, &owners, &user, &pass, &controls)
+            }
+        })
+    }
+
+    // Run until asked to shut down
+    pub fn run(&mut self) {
+        loop {
+            // Sleep until something happens, or until the next worker is due a restart
+            let next = self.workers.values().filter_map(|w| w.restart_at).min();
+            let event = match next {
+                Some(at) => {
+                    let now = Instant::now();
+                    let timeout = if at > now { at - now } else { Duration::from_secs(0) };
+                    match self.events.recv_timeout(timeout) {
+                        Ok(event) => Some(event),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        // MainState holds a sender, so this can't happen
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                None => match self.events.recv() {
+                    Ok(event) => Some(event),
+                    Err(_) => return,
+                },
+            };
+            match event {
+                Some(Event::Exited(name, generation)) => self.exited(&name, generation),
+                Some(Event::Reload) => self.reload(),
+                Some(Event::Shutdown) => return self.shutdown(),
+                None => {}
+            }
+
+            let now = Instant::now();
+            let due: Vec<String> = self.workers
+                .iter()
+                .filter(|(_, w)| w.restart_at.map_or(false, |at| at <= now))
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in due {
+                let (channels, shared) = {
+                    let worker = &self.workers[&name];
+                    let channels = worker.channels
+                        .iter()
+                        .map(|(state, channel)| (ThreadState::recover(state), channel.clone()))
+                        .collect();
+                    (channels, worker.shared)
+                };
+                println!("Restarting worker for {}", name);
+                self.start(name, channels, shared);
+            }
+        }
+    }
+
+    // Ask every running worker to part and close up, and wait for them to finish. A second
+    // shutdown request, e.g. another ^C, stops waiting.
+    fn shutdown(&mut self) {
+        println!("Shutting down");
+        let mut running: Vec<String> = Vec::new();
+        for (name, worker) in &self.workers {
+            if worker.handle.is_some() {
+                worker.controls.lock().unwrap().push(Control::Shutdown);
+                running.push(name.clone());
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(SHUTDOWN_TIMEOUT);
+        while !running.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                println!("Gave up waiting for {}", running.join(", "));
+                return;
+            }
+            match self.events.recv_timeout(deadline - now) {
+                Ok(Event::Exited(name, generation)) => {
+                    let worker = match self.workers.get_mut(&name) {
+                        Some(worker) if worker.generation == generation => worker,
+                        _ => continue,
+                    };
+                    if let Some(handle) = worker.handle.take() {
+                        let _ = handle.join();
+                    }
+                    running.retain(|running| *running != name);
+                }
+                Ok(Event::Shutdown) => return,
+                Ok(Event::Reload) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    // Read config.toml again and bring the running channels in line with it. Channels are joined,
+    // parted and updated in place; anything that needs a new connection waits for a restart.
+    fn reload(&mut self) {
+        println!("Reloading {}", self.paths.path.display());
+        let cfg = match self.paths.open() {
+            Ok(cfg) => cfg,
+            Err(errors) => {
+                let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                self.notify(format!("Reload failed, keeping the old config: {}", errors.join("; ")));
+                return;
+            }
+        };
+
+        let by_name = |cfg: &Config| -> HashMap<String, Channel> {
+            cfg.channels
+                .values()
+                .map(|channel| (channel.name.to_lowercase(), channel.clone()))
+                .collect()
+        };
+        let old = by_name(&self.cfg);
+        let new = by_name(&cfg);
+
+        let (mut joined, mut parted, mut updated) = (0, 0, 0);
+        for (name, channel) in &new {
+            match old.get(name) {
+                None => {
+                    self.join(channel);
+                    joined += 1;
+                }
+                Some(old) if old != channel => {
+                    self.update(name, channel);
+                    updated += 1;
+                }
+                Some(_) => {}
+            }
+        }
+        for name in old.keys().filter(|name| !new.contains_key(*name)) {
+            self.part(name);
+            parted += 1;
+        }
+
+        let mut notice = format!(
+            "Reloaded config: {} joined, {} parted, {} updated",
+            joined, parted, updated
+        );
+        if cfg.user != self.cfg.user
+            || cfg.pass != self.cfg.pass
+            || cfg.owners != self.cfg.owners
+            || cfg.shared_connection != self.cfg.shared_connection
+        {
+            notice.push_str(". Changes to user, pass, owners or shared_connection need a restart");
+        }
+        self.cfg.channels = cfg.channels;
+        self.notify(notice);
+    }
+
+    fn join(&mut self, channel: &Channel) {
+        let t_state = ThreadState::new(Arc::clone(&self.main));
+        if !self.cfg.shared_connection {
+            self.start(format!("#{}", channel.name), vec![(t_state, channel.clone())], false);
+        } else if let Some(worker) = self.workers.get_mut("shared") {
+            worker.controls.lock().unwrap().push(Control::Join(Arc::clone(&t_state), channel.clone()));
+            worker.channels.push((t_state, channel.clone()));
+        }
+    }
+
+    fn part(&mut self, name: &str) {
+        let worker_name = match self.worker_of(name) {
+            Some(worker_name) => worker_name,
+            None => return,
+        };
+        if let Some(worker) = self.workers.get_mut(&worker_name) {
+            worker.controls.lock().unwrap().push(Control::Part(name.to_string()));
+            worker.channels.retain(|(_, channel)| channel.name.to_lowercase() != name);
+        }
+
+        // A thread of its own ends once its channel is parted
+        if !self.cfg.shared_connection {
+            self.workers.remove(&worker_name);
+            self.main.lock().unwrap().health.remove(&worker_name);
+        }
+    }
+
+    fn update(&mut self, name: &str, channel: &Channel) {
+        let worker_name = match self.worker_of(name) {
+            Some(worker_name) => worker_name,
+            None => return,
+        };
+        if let Some(worker) = self.workers.get_mut(&worker_name) {
+            worker.controls.lock().unwrap().push(Control::Update(channel.clone()));
+            for (_, old) in worker.channels.iter_mut().filter(|(_, old)| old.name.to_lowercase() == name) {
+                *old = channel.clone();
+            }
+        }
+    }
+
+    // The worker running a channel, by its lowercase name
+    fn worker_of(&self, name: &str) -> Option<String> {
+        self.workers
+            .iter()
+            .find(|(_, w)| w.channels.iter().any(|(_, channel)| channel.name.to_lowercase() == name))
+            .map(|(worker_name, _)| worker_name.clone())
+    }
+
+    fn notify(&self, notice: String) {
+        println!("{}", notice);
+        self.main.lock().unwrap().notices.push(notice);
+    }
+
+    // A worker's thread has ended, either by panicking or by returning, which it only should when
+    // its channel can't be run. Restarting wouldn't help then.
+    fn exited(&mut self, name: &str, generation: u64) {
+        // A thread that was parted, or replaced since, is no longer ours to join
+        let worker = match self.workers.get_mut(name) {
+            Some(worker) if worker.generation == generation => worker,
+            _ => return,
+        };
+        let reason = match worker.handle.take().map(JoinHandle::join) {
+            Some(Ok(Err(why))) => return self.failed(name, why),
+            Some(Err(panic)) => panic_message(panic),
+            _ => String::from("thread exited"),
+        };
+
+        // Only count recent crashes towards the limit
+        let now = Instant::now();
+        worker.crashes.push_back(now);
+        while worker.crashes.len() > MAX_RESTARTS
+            || worker.crashes.front().map_or(false, |&at| now - at > Duration::from_secs(RESTART_WINDOW))
+        {
+            worker.crashes.pop_front();
+        }
+        if worker.crashes.len() == 1 {
+            worker.backoff.reset();
+        }
+
+        let status;
+        let notice;
+        if worker.crashes.len() >= MAX_RESTARTS {
+            status = Status::Failed;
+            notice = format!(
+                "{} crashed {} times in {} minutes, giving up: {}",
+                name, MAX_RESTARTS, RESTART_WINDOW / 60, reason
+            );
+        } else {
+            let delay = worker.backoff.next_delay();
+            worker.restart_at = Some(now + delay);
+            status = Status::Restarting;
+            notice = format!("{} crashed, restarting in {}s: {}", name, delay.as_secs() + 1, reason);
+        }
+        println!("{}", notice);
+
+        let mut main = self.main.lock().unwrap();
+        if let Some(health) = main.health.get_mut(name) {
+            health.status = status;
+            if status == Status::Restarting {
+                health.restarts += 1;
+            }
+            health.last_crash = Some(reason);
+        }
+        main.notices.push(notice);
+    }
+
+    fn failed(&mut self, name: &str, why: String) {
+        let notice = format!("{} can't run, not restarting it: {}", name, why);
+        println!("{}", notice);
+
+        let mut main = self.main.lock().unwrap();
+        if let Some(health) = main.health.get_mut(name) {
+            health.status = Status::Failed;
+            health.last_crash = Some(why);
+        }
+        main.notices.push(notice);
+    }
+}
+
+// Tells the supervisor when a channel's thread ends, whether it returned or panicked
+struct ExitGuard {
+    name: String,
+    generation: u64,
+    tx: Sender<Event>,
+}
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        let _ = self.tx.send(Event::Exited(self.name.clone(), self.generation));
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
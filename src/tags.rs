@@ -0,0 +1,146 @@
+use irc::proto::message::Tag;
+
+// The IRCv3 tags Twitch sends with a chat message. Tags that are missing or empty are left as
+// their defaults.
+#[derive(Clone, Debug, Default)]
+pub struct Tags {
+    pub id: Option<String>,
+    pub display_name: Option<String>,
+    pub user_id: Option<String>,
+    pub room_id: Option<String>,
+    // e.g. `broadcaster/1`, `subscriber/3012`
+    pub badges: Vec<Badge>,
+    // More about some of the badges, e.g. `subscriber/14` for months subscribed
+    pub badge_info: Vec<Badge>,
+    // Hex RGB, e.g. `#1E90FF`, if the user picked one
+    pub color: Option<String>,
+    // Bits cheered with this message
+    pub bits: Option<u32>,
+    pub emotes: Vec<Emote>,
+    // The user's first message in the channel
+    pub first_msg: bool,
+    pub reply_parent: Option<ReplyParent>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Badge {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Emote {
+    pub id: String,
+    // Char positions in the message, both ends inclusive
+    pub ranges: Vec<(usize, usize)>,
+}
+
+// The message this one is a reply to
+#[derive(Clone, Debug, Default)]
+pub struct ReplyParent {
+    pub msg_id: String,
+    pub user_id: Option<String>,
+    pub user_login: Option<String>,
+    pub display_name: Option<String>,
+    pub msg_body: Option<String>,
+}
+
+impl Tags {
+    pub fn parse(tags: &Option<Vec<Tag>>) -> Tags {
+        let mut parsed = Tags::default();
+        let mut reply = ReplyParent::default();
+        let tags = match tags {
+            Some(tags) => tags,
+            None => return parsed,
+        };
+        for Tag(key, val) in tags {
+            let val = match val {
+                Some(val) if !val.is_empty() => unescape(val),
+                _ => continue,
+            };
+            match key.as_str() {
+                "id" => parsed.id = Some(val),
+                "display-name" => parsed.display_name = Some(val),
+                "user-id" => parsed.user_id = Some(val),
+                "room-id" => parsed.room_id = Some(val),
+                "badges" => parsed.badges = parse_badges(&val),
+                "badge-info" => parsed.badge_info = parse_badges(&val),
+                "color" => parsed.color = Some(val),
+                "bits" => parsed.bits = val.parse().ok(),
+                "emotes" => parsed.emotes = parse_emotes(&val),
+                "first-msg" => parsed.first_msg = val == "1",
+                "reply-parent-msg-id" => reply.msg_id = val,
+                "reply-parent-user-id" => reply.user_id = Some(val),
+                "reply-parent-user-login" => reply.user_login = Some(val),
+                "reply-parent-display-name" => reply.display_name = Some(val),
+                "reply-parent-msg-body" => reply.msg_body = Some(val),
+                _ => {}
+            }
+        }
+        if !reply.msg_id.is_empty() {
+            parsed.reply_parent = Some(reply);
+        }
+        parsed
+    }
+
+    pub fn has_badge(&self, name: &str) -> bool {
+        self.badges.iter().any(|badge| badge.name == name)
+    }
+}
+
+// `name/version,name/version`
+fn parse_badges(val: &str) -> Vec<Badge> {
+    val.split(',')
+        .filter_map(|badge| {
+            let mut parts = badge.splitn(2, '/');
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            Some(Badge {
+                name: name.to_string(),
+                version: parts.next().unwrap_or("").to_string(),
+            })
+        })
+        .collect()
+}
+
+// `id:start-end,start-end/id:start-end`
+fn parse_emotes(val: &str) -> Vec<Emote> {
+    val.split('/')
+        .filter_map(|emote| {
+            let mut parts = emote.splitn(2, ':');
+            let id = parts.next()?;
+            let ranges = parts
+                .next()?
+                .split(',')
+                .filter_map(|range| {
+                    let mut ends = range.splitn(2, '-');
+                    Some((ends.next()?.parse().ok()?, ends.next()?.parse().ok()?))
+                })
+                .collect();
+            Some(Emote {
+                id: id.to_string(),
+                ranges,
+            })
+        })
+        .collect()
+}
+
+// Undo the IRCv3 escaping of tag values
+fn unescape(val: &str) -> String {
+    let mut out = String::with_capacity(val.len());
+    let mut chars = val.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
@@ -0,0 +1,197 @@
+use custom;
+
+// An alias's arguments with placeholders for the caller's, e.g. `give $2 ${1:-1}`:
+//   `$1`            the first argument
+//   `$2-`           the second argument on, space separated
+//   `${1:-default}` the first argument, or `default` if there isn't one. `${2-:-default}` too.
+//   `$$`            a literal `$`, e.g. `Donate $$5`
+// A `$` that doesn't start one of these is left as it is.
+pub struct Template<'a> {
+    pieces: Vec<Piece<'a>>,
+}
+
+enum Piece<'a> {
+    Text(&'a str),
+    Arg {
+        // Counting from 1
+        index: usize,
+        rest: bool,
+        default: Option<&'a str>,
+    },
+}
+
+impl<'a> Template<'a> {
+    pub fn parse(s: &'a str) -> Template<'a> {
+        let mut pieces = Vec::new();
+        let mut text = 0;
+        let mut i = 0;
+        while let Some(pos) = s[i..].find('$') {
+            let start = i + pos;
+            if s[start + 1..].starts_with('$') {
+                pieces.push(Piece::Text(&s[text..start + 1]));
+                i = start + 2;
+                text = i;
+                continue;
+            }
+            match parse_arg(&s[start + 1..]) {
+                Some((piece, len)) => {
+                    if text < start {
+                        pieces.push(Piece::Text(&s[text..start]));
+                    }
+                    pieces.push(piece);
+                    i = start + 1 + len;
+                    text = i;
+                }
+                None => i = start + 1,
+            }
+        }
+        if text < s.len() {
+            pieces.push(Piece::Text(&s[text..]));
+        }
+        Template { pieces }
+    }
+
+    pub fn has_args(&self) -> bool {
+        self.pieces.iter().any(|piece| match piece {
+            Piece::Arg { .. } => true,
+            Piece::Text(_) => false,
+        })
+    }
+
+    // How many arguments it takes to fill in every placeholder without a default
+    pub fn required(&self) -> usize {
+        self.pieces
+            .iter()
+            .filter_map(|piece| match piece {
+                Piece::Arg { index, default: None, .. } => Some(*index),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Missing arguments without a default are left empty. Arguments that would start the result
+    // lose a leading `/` or `.`, or a viewer could make the bot run chat commands with `say $1`.
+    pub fn fill(&self, args: &[&str]) -> String {
+        let mut out = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Arg { index, rest, default } => {
+                    let value = if *rest {
+                        args.get(index - 1..).map_or_else(String::new, |args| args.join(" "))
+                    } else {
+                        args.get(index - 1).map_or_else(String::new, |arg| arg.to_string())
+                    };
+                    match default {
+                        Some(default) if value.is_empty() => out.push_str(default),
+                        _ => {
+                            let value = custom::guard(&out, &value);
+                            out.push_str(&value);
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+// The placeholder at the start of `s`, which follows a `$`, and how long it is
+fn parse_arg(s: &str) -> Option<(Piece, usize)> {
+    if s.starts_with('{') {
+        let end = s.find('}')?;
+        let inner = &s[1..end];
+        let (spec, default) = match inner.find(":-") {
+            Some(pos) => (&inner[..pos], Some(&inner[pos + 2..])),
+            None => (inner, None),
+        };
+        let (index, rest, len) = parse_index(spec)?;
+        if len != spec.len() {
+            return None;
+        }
+        Some((Piece::Arg { index, rest, default }, end + 1))
+    } else {
+        let (index, rest, len) = parse_index(s)?;
+        Some((Piece::Arg { index, rest, default: None }, len))
+    }
+}
+
+// `2` or `2-` at the start of `s`: the index, whether it was `2-`, and how much of `s` it took
+fn parse_index(s: &str) -> Option<(usize, bool, usize)> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or_else(|| s.len());
+    let index = s[..digits].parse::<usize>().ok().filter(|&index| index > 0)?;
+    if s[digits..].starts_with('-') {
+        Some((index, true, digits + 1))
+    } else {
+        Some((index, false, digits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(template: &str, args: &[&str]) -> String {
+        Template::parse(template).fill(args)
+    }
+
+    #[test]
+    fn positional() {
+        assert_eq!(fill("give $2 $1", &["a", "b"]), "give b a");
+        assert_eq!(fill("give $1", &[]), "give ");
+        assert_eq!(Template::parse("give $2 $1").required(), 2);
+    }
+
+    #[test]
+    fn rest() {
+        assert_eq!(fill("say $2-", &["a", "b", "c"]), "say b c");
+        assert_eq!(fill("say $2-", &["a"]), "say ");
+        assert_eq!(fill("say $2-!", &["a", "b"]), "say b!");
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(fill("hug ${1:-everyone}", &[]), "hug everyone");
+        assert_eq!(fill("hug ${1:-everyone}", &["Zed"]), "hug Zed");
+        assert_eq!(fill("from ${2-:-nobody}", &["a", "b", "c"]), "from b c");
+        assert_eq!(fill("hug ${1:-}", &[]), "hug ");
+        assert_eq!(Template::parse("hug ${1:-x} $2").required(), 2);
+        assert_eq!(Template::parse("hug ${3:-x}").required(), 0);
+    }
+
+    #[test]
+    fn not_placeholders() {
+        for s in &["$0", "cost $", "$ 1", "$x", "${1", "${x}", "${0:-a}", "${1-2}"] {
+            let template = Template::parse(s);
+            assert!(!template.has_args(), "{}", s);
+            assert_eq!(template.fill(&["a"]), *s);
+        }
+    }
+
+    #[test]
+    fn escaped() {
+        assert_eq!(fill("Donate $$5", &["a"]), "Donate $5");
+        assert_eq!(fill("$$$1", &["a"]), "$a");
+        assert_eq!(fill("$${1}", &["a"]), "${1}");
+        assert_eq!(fill("$$", &[]), "$");
+        assert_eq!(Template::parse("Donate $$5").required(), 0);
+    }
+
+    #[test]
+    fn multibyte() {
+        assert_eq!(fill("é$1é ${1:-ü}", &["ö"]), "éöé ö");
+        assert_eq!(fill("ü$", &[]), "ü$");
+    }
+
+    #[test]
+    fn guarded() {
+        assert_eq!(fill("$1", &["/ban someone"]), "ban someone");
+        assert_eq!(fill("$1", &[".timeout"]), "timeout");
+        assert_eq!(fill("$2-", &["a", " ./clear"]), "clear");
+        assert_eq!(fill("say $1", &["/ban"]), "say /ban");
+        // What the alias was made with is up to whoever made it
+        assert_eq!(fill("/me ${1:-/waves}", &[]), "/me /waves");
+        assert_eq!(fill("${1:-/slow 5}", &[]), "/slow 5");
+    }
+}
@@ -0,0 +1,728 @@
+use auth::Permissions;
+use cmd;
+use csv;
+use regex::Regex;
+use rusqlite::{self, Connection};
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Table {
+    Quotes,
+    Aliases,
+    Custom,
+}
+
+impl Table {
+    pub fn parse(s: &str) -> Option<Table> {
+        match s {
+            "quotes" => Some(Table::Quotes),
+            "aliases" => Some(Table::Aliases),
+            "custom" => Some(Table::Custom),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    // A Nightbot custom command export, imported as custom commands
+    Nightbot,
+    // A Streamlabs Chatbot quote export
+    Streamlabs,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "nightbot" => Some(Format::Nightbot),
+            "streamlabs" => Some(Format::Streamlabs),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuoteRecord {
+    #[serde(default)]
+    pub id: Option<u32>,
+    pub quote: String,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub added_by: Option<String>,
+    // Unix time
+    #[serde(default)]
+    pub added_at: Option<i64>,
+    #[serde(default)]
+    pub deleted_at: Option<i64>,
+    #[serde(default)]
+    pub deleted_by: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AliasRecord {
+    pub alias: String,
+    pub command: String,
+    // Permission letters, as given to !alias
+    pub auth: String,
+    #[serde(default)]
+    pub bucket_count: Option<u32>,
+    #[serde(default)]
+    pub bucket_secs: Option<i64>,
+    #[serde(default)]
+    pub user_bucket_count: Option<u32>,
+    #[serde(default)]
+    pub user_bucket_secs: Option<i64>,
+    // Whose members may also use it
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub min_args: Option<u32>,
+    #[serde(default)]
+    pub max_args: Option<u32>,
+    // JSON, as stored
+    #[serde(default)]
+    pub arg_patterns: Option<String>,
+    #[serde(default)]
+    pub added_by: Option<String>,
+    // Unix time
+    #[serde(default)]
+    pub added_at: Option<i64>,
+}
+
+// A command made with !cmd
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CustomRecord {
+    pub name: String,
+    pub response: String,
+    // Permission letters, as given to !cmd
+    pub auth: String,
+    // How many times it's been used
+    #[serde(default)]
+    pub count: u32,
+    #[serde(default)]
+    pub added_by: Option<String>,
+    // Unix time
+    #[serde(default)]
+    pub added_at: Option<i64>,
+}
+
+// Written into JSON exports. Files from before it was are version 1, when `;` in an alias
+// command didn't split it into several and `$` didn't start placeholders.
+const FORMAT_VERSION: u32 = 2;
+
+fn first_version() -> u32 {
+    1
+}
+
+// The JSON format. Any table may be left out.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Dump {
+    #[serde(default = "first_version")]
+    version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quotes: Option<Vec<QuoteRecord>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aliases: Option<Vec<AliasRecord>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    custom: Option<Vec<CustomRecord>>,
+}
+
+// Write the given tables of a channel database
+pub fn export<W: Write>(db: &Connection, tables: &[Table], format: Format, mut out: W) -> Result<(), TransferError> {
+    match format {
+        Format::Json => {
+            let mut dump = Dump {
+                version: FORMAT_VERSION,
+                ..Dump::default()
+            };
+            if tables.contains(&Table::Quotes) {
+                dump.quotes = Some(read_quotes(db)?);
+            }
+            if tables.contains(&Table::Aliases) {
+                dump.aliases = Some(read_aliases(db)?);
+            }
+            if tables.contains(&Table::Custom) {
+                dump.custom = Some(read_custom(db)?);
+            }
+            serde_json::to_writer_pretty(&mut out, &dump)?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            match tables {
+                [Table::Quotes] => for quote in read_quotes(db)? {
+                    writer.serialize(quote)?;
+                },
+                [Table::Aliases] => for alias in read_aliases(db)? {
+                    writer.serialize(alias)?;
+                },
+                [Table::Custom] => for custom in read_custom(db)? {
+                    writer.serialize(custom)?;
+                },
+                _ => return Err(TransferError::Unsupported("a CSV file holds one table, pick one with --table")),
+            }
+            writer.flush()?;
+        }
+        Format::Nightbot | Format::Streamlabs => {
+            return Err(TransferError::Unsupported("only JSON and CSV can be exported"));
+        }
+    }
+    Ok(())
+}
+
+fn read_quotes(db: &Connection) -> rusqlite::Result<Vec<QuoteRecord>> {
+    let mut stmt = db.prepare(
+        "SELECT id, quote, author, category, added_by, added_at, deleted_at, deleted_by
+         FROM quote ORDER BY id",
+    )?;
+    let rows = stmt.query_map(&[], |row| QuoteRecord {
+        id: row.get(0),
+        quote: row.get(1),
+        author: row.get(2),
+        category: row.get(3),
+        added_by: row.get(4),
+        added_at: row.get(5),
+        deleted_at: row.get(6),
+        deleted_by: row.get(7),
+    })?;
+    rows.collect()
+}
+
+fn read_aliases(db: &Connection) -> rusqlite::Result<Vec<AliasRecord>> {
+    let mut stmt = db.prepare(
+        "SELECT alias, command, auth, bucket_count, bucket_secs, user_bucket_count, user_bucket_secs, role,
+                min_args, max_args, arg_patterns, added_by, added_at
+         FROM alias ORDER BY alias",
+    )?;
+    let rows = stmt.query_map(&[], |row| AliasRecord {
+        alias: row.get(0),
+        command: row.get(1),
+        auth: Permissions::from_bits_truncate(row.get(2)).to_letters(),
+        bucket_count: row.get(3),
+        bucket_secs: row.get(4),
+        user_bucket_count: row.get(5),
+        user_bucket_secs: row.get(6),
+        role: row.get(7),
+        min_args: row.get(8),
+        max_args: row.get(9),
+        arg_patterns: row.get(10),
+        added_by: row.get(11),
+        added_at: row.get(12),
+    })?;
+    rows.collect()
+}
+
+fn read_custom(db: &Connection) -> rusqlite::Result<Vec<CustomRecord>> {
+    let mut stmt = db.prepare("SELECT name, response, auth, count, added_by, added_at FROM custom_cmd ORDER BY name")?;
+    let rows = stmt.query_map(&[], |row| CustomRecord {
+        name: row.get(0),
+        response: row.get(1),
+        auth: Permissions::from_bits_truncate(row.get(2)).to_letters(),
+        count: row.get::<_, i64>(3) as u32,
+        added_by: row.get(4),
+        added_at: row.get(5),
+    })?;
+    rows.collect()
+}
+
+// What an import did, or would do on a dry run
+#[derive(Debug, Default)]
+pub struct Report {
+    // Ids in the source, or the record number if it had none, and the ids they were given
+    pub quotes: Vec<(u32, u32)>,
+    // Quotes whose text is already in the database, and the id it's under
+    pub duplicate_quotes: Vec<(u32, u32)>,
+    // Quotes imported without a date because theirs couldn't be read, and what it was
+    pub undated_quotes: Vec<(u32, String)>,
+    pub aliases: Vec<String>,
+    // Aliases that weren't imported, and why
+    pub skipped_aliases: Vec<(String, String)>,
+    pub custom: Vec<String>,
+    // Custom commands that weren't imported, and why
+    pub skipped_custom: Vec<(String, String)>,
+    // Things that were imported, but not quite as they were
+    pub warnings: Vec<String>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} quotes imported, {} duplicates skipped",
+            self.quotes.len(),
+            self.duplicate_quotes.len()
+        )?;
+        for (old, new) in &self.quotes {
+            writeln!(f, "  #{} -> #{}", old, new)?;
+        }
+        for (old, existing) in &self.duplicate_quotes {
+            writeln!(f, "  #{} is a duplicate of #{}", old, existing)?;
+        }
+        if !self.undated_quotes.is_empty() {
+            writeln!(f, "{} quotes had a date that couldn't be read, and have none", self.undated_quotes.len())?;
+        }
+        for (old, date) in &self.undated_quotes {
+            writeln!(f, "  #{}: `{}`", old, date)?;
+        }
+        writeln!(
+            f,
+            "{} aliases imported, {} skipped",
+            self.aliases.len(),
+            self.skipped_aliases.len()
+        )?;
+        for alias in &self.aliases {
+            writeln!(f, "  {}", alias)?;
+        }
+        for (alias, reason) in &self.skipped_aliases {
+            writeln!(f, "  {}: {}", alias, reason)?;
+        }
+        writeln!(
+            f,
+            "{} custom commands imported, {} skipped",
+            self.custom.len(),
+            self.skipped_custom.len()
+        )?;
+        for name in &self.custom {
+            writeln!(f, "  {}", name)?;
+        }
+        for (name, reason) in &self.skipped_custom {
+            writeln!(f, "  {}: {}", name, reason)?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "Warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+// Add records to a channel database. Quotes always get new ids, reported alongside the old ones.
+// CSV files don't record a version, so `legacy` says whether their aliases need escaping like those
+// of an old JSON file. Nothing is written on a dry run.
+pub fn import<R: Read>(
+    db: &mut Connection,
+    table: Option<Table>,
+    format: Format,
+    input: R,
+    legacy: bool,
+    dry_run: bool,
+) -> Result<Report, TransferError> {
+    let mut report = Report::default();
+    let (quotes, aliases, custom) = match format {
+        Format::Json => {
+            let mut dump: Dump = serde_json::from_reader(input)?;
+            if dump.version > FORMAT_VERSION {
+                return Err(TransferError::Unsupported("the file is from a newer version of zbot"));
+            }
+            if dump.version < 2 {
+                for alias in dump.aliases.iter_mut().flat_map(|aliases| aliases.iter_mut()) {
+                    alias.command = escape_legacy(&alias.command);
+                }
+            }
+            let wanted = |t| table.map_or(true, |table| table == t);
+            let quotes = if wanted(Table::Quotes) { dump.quotes } else { None };
+            let aliases = if wanted(Table::Aliases) { dump.aliases } else { None };
+            let custom = if wanted(Table::Custom) { dump.custom } else { None };
+            (quotes.unwrap_or_default(), aliases.unwrap_or_default(), custom.unwrap_or_default())
+        }
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            match table {
+                Some(Table::Aliases) => {
+                    let mut aliases: Vec<AliasRecord> = reader.deserialize().collect::<Result<_, _>>()?;
+                    if legacy {
+                        for alias in &mut aliases {
+                            alias.command = escape_legacy(&alias.command);
+                        }
+                    }
+                    (Vec::new(), aliases, Vec::new())
+                }
+                Some(Table::Custom) => (Vec::new(), Vec::new(), reader.deserialize().collect::<Result<_, _>>()?),
+                _ => (reader.deserialize().collect::<Result<_, _>>()?, Vec::new(), Vec::new()),
+            }
+        }
+        Format::Nightbot => (Vec::new(), Vec::new(), nightbot_commands(input, &mut report)?),
+        Format::Streamlabs => (streamlabs_quotes(input, &mut report)?, Vec::new(), Vec::new()),
+    };
+
+    let tx = db.transaction()?;
+    import_quotes(&tx, quotes, &mut report)?;
+    import_aliases(&tx, aliases, &mut report)?;
+    import_custom(&tx, custom, &mut report)?;
+    if !dry_run {
+        tx.commit()?;
+    }
+    Ok(report)
+}
+
+// An alias command from an export older than chains and placeholders, escaped to say what it did
+fn escape_legacy(command: &str) -> String {
+    command.replace('$', "$$").replace(';', ";;")
+}
+
+// Quotes are compared ignoring case and spacing
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn import_quotes(db: &Connection, quotes: Vec<QuoteRecord>, report: &mut Report) -> rusqlite::Result<()> {
+    let mut known: HashMap<String, u32> = HashMap::new();
+    {
+        let mut stmt = db.prepare("SELECT id, quote FROM quote")?;
+        let rows = stmt.query_map(&[], |row| (row.get::<_, u32>(0), row.get::<_, String>(1)))?;
+        for row in rows {
+            let (id, text) = row?;
+            known.insert(normalize(&text), id);
+        }
+    }
+
+    for (i, quote) in quotes.into_iter().enumerate() {
+        let old = quote.id.unwrap_or(i as u32 + 1);
+        let key = normalize(&quote.quote);
+        if let Some(&existing) = known.get(&key) {
+            report.duplicate_quotes.push((old, existing));
+            continue;
+        }
+        db.execute(
+            "INSERT INTO quote (quote, author, category, added_by, added_at, deleted_at, deleted_by)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            &[
+                &quote.quote,
+                &quote.author,
+                &quote.category,
+                &quote.added_by,
+                &quote.added_at,
+                &quote.deleted_at,
+                &quote.deleted_by,
+            ],
+        )?;
+        let new = db.last_insert_rowid() as u32;
+        known.insert(key, new);
+        report.quotes.push((old, new));
+    }
+    Ok(())
+}
+
+fn import_aliases(db: &Connection, aliases: Vec<AliasRecord>, report: &mut Report) -> rusqlite::Result<()> {
+    for alias in aliases {
+        let auth = match Permissions::from_letters(&alias.auth) {
+            Some(auth) => auth,
+            None => {
+                let reason = format!("unknown permissions `{}`", alias.auth);
+                report.skipped_aliases.push((alias.alias, reason));
+                continue;
+            }
+        };
+        if let (Some(min), Some(max)) = (alias.min_args, alias.max_args) {
+            if min > max {
+                let reason = String::from("min_args is more than max_args");
+                report.skipped_aliases.push((alias.alias, reason));
+                continue;
+            }
+        }
+        let arg_patterns = alias.arg_patterns.as_ref().and_then(|json| valid_patterns(json));
+        let exists: i64 = db.query_row("SELECT COUNT(*) FROM alias WHERE alias=?1", &[&alias.alias], |row| {
+            row.get(0)
+        })?;
+        let custom: i64 = db.query_row("SELECT COUNT(*) FROM custom_cmd WHERE name=?1", &[&alias.alias], |row| {
+            row.get(0)
+        })?;
+        if exists > 0 {
+            report.skipped_aliases.push((alias.alias, String::from("already exists")));
+            continue;
+        }
+        // It would hide the custom command
+        if custom > 0 {
+            report.skipped_aliases.push((alias.alias, String::from("already a command")));
+            continue;
+        }
+        db.execute(
+            "INSERT INTO alias (auth, alias, command, bucket_count, bucket_secs, user_bucket_count, user_bucket_secs, role,
+                                min_args, max_args, arg_patterns, added_by, added_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            &[
+                &auth.bits(),
+                &alias.alias,
+                &alias.command,
+                &alias.bucket_count,
+                &alias.bucket_secs,
+                &alias.user_bucket_count,
+                &alias.user_bucket_secs,
+                &alias.role,
+                &alias.min_args,
+                &alias.max_args,
+                &arg_patterns,
+                &alias.added_by,
+                &alias.added_at,
+            ],
+        )?;
+        report.aliases.push(alias.alias);
+    }
+    Ok(())
+}
+
+// Keep the argument patterns `!aliasmod` would have taken: positions from 1, and regexes that
+// compile. None if that leaves nothing.
+fn valid_patterns(json: &str) -> Option<String> {
+    let patterns: BTreeMap<String, String> = serde_json::from_str(json).ok()?;
+    let patterns: BTreeMap<usize, String> = patterns
+        .into_iter()
+        .filter_map(|(index, pattern)| {
+            let index = index.parse::<usize>().ok().filter(|&index| index > 0)?;
+            Regex::new(&pattern).ok()?;
+            Some((index, pattern))
+        })
+        .collect();
+    if patterns.is_empty() {
+        None
+    } else {
+        serde_json::to_string(&patterns).ok()
+    }
+}
+
+// As with !cmd, names already used by a command or alias are taken
+fn import_custom(db: &Connection, custom: Vec<CustomRecord>, report: &mut Report) -> rusqlite::Result<()> {
+    for record in custom {
+        let auth = match Permissions::from_letters(&record.auth) {
+            Some(auth) => auth,
+            None => {
+                let reason = format!("unknown permissions `{}`", record.auth);
+                report.skipped_custom.push((record.name, reason));
+                continue;
+            }
+        };
+        let aliased: i64 = db.query_row("SELECT COUNT(*) FROM alias WHERE alias=?1", &[&record.name], |row| {
+            row.get(0)
+        })?;
+        let taken: i64 = db.query_row("SELECT COUNT(*) FROM custom_cmd WHERE name=?1", &[&record.name], |row| {
+            row.get(0)
+        })?;
+        let reason = if cmd::is_builtin(&record.name) || aliased > 0 {
+            Some("already a command")
+        } else if taken > 0 {
+            Some("already exists")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            report.skipped_custom.push((record.name, String::from(reason)));
+            continue;
+        }
+        db.execute(
+            "INSERT INTO custom_cmd (name, response, auth, count, added_by, added_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            &[
+                &record.name,
+                &record.response,
+                &auth.bits(),
+                &record.count,
+                &record.added_by,
+                &record.added_at,
+            ],
+        )?;
+        report.custom.push(record.name);
+    }
+    Ok(())
+}
+
+// Nightbot's export is `{"commands": [...]}`, or just the list
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NightbotExport {
+    Wrapped { commands: Vec<NightbotCommand> },
+    Bare(Vec<NightbotCommand>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NightbotCommand {
+    name: String,
+    message: String,
+    #[serde(default)]
+    user_level: Option<String>,
+    #[serde(default)]
+    count: Option<u32>,
+}
+
+// Each command becomes a custom command, whose variables are mostly Nightbot's own. Nightbot's user
+// levels include everyone above them, in the order owner, moderator, twitch_vip, regular,
+// subscriber, everyone. Custom commands all share one cooldown, so Nightbot's is dropped.
+fn nightbot_commands<R: Read>(input: R, report: &mut Report) -> Result<Vec<CustomRecord>, TransferError> {
+    let commands = match serde_json::from_reader(input)? {
+        NightbotExport::Wrapped { commands } => commands,
+        NightbotExport::Bare(commands) => commands,
+    };
+    let mut custom = Vec::new();
+    for command in commands {
+        let name = command.name.trim_start_matches('!').to_string();
+        // Never open a command up to more people than Nightbot did
+        let auth = match command.user_level.as_ref().map(String::as_str) {
+            Some("owner") => "b",
+            Some("moderator") => "bm",
+            Some("twitch_vip") => "bmi",
+            Some("regular") => {
+                report.warnings.push(format!("{} was for Nightbot regulars, which zbot doesn't have, so it's for mods only", name));
+                "bm"
+            }
+            Some("subscriber") => "bms",
+            Some("everyone") | None => "bmsv",
+            Some(level) => {
+                report.skipped_custom.push((name, format!("unknown Nightbot user level `{}`", level)));
+                continue;
+            }
+        };
+        custom.push(CustomRecord {
+            name,
+            response: command.message,
+            auth: auth.to_string(),
+            count: command.count.unwrap_or(0),
+            added_by: None,
+            added_at: None,
+        });
+    }
+    Ok(custom)
+}
+
+// Streamlabs Chatbot's quote export is a CSV file with a header. Columns are found by name.
+fn streamlabs_quotes<R: Read>(input: R, report: &mut Report) -> Result<Vec<QuoteRecord>, TransferError> {
+    let mut reader = csv::Reader::from_reader(input);
+    let headers: Vec<String> = reader.headers()?.iter().map(|h| h.trim().to_lowercase()).collect();
+    let column = |names: &[&str]| headers.iter().position(|h| names.contains(&h.as_str()));
+    let id = column(&["id", "#"]);
+    let text = column(&["quote", "text"])
+        .ok_or(TransferError::Unsupported("no `quote` column in the Streamlabs export"))?;
+    let author = column(&["author", "user", "quotee"]);
+    let category = column(&["game", "category"]);
+    let date = column(&["date", "created", "created_at"]);
+
+    let date_db = Connection::open_in_memory()?;
+    let mut quotes = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let field = |i: Option<usize>| {
+            i.and_then(|i| record.get(i))
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+        };
+        let old_id = field(id).and_then(|id| id.parse().ok());
+        let added_at = match field(date) {
+            Some(date) => {
+                let added_at = parse_date(&date_db, &date)?;
+                if added_at.is_none() {
+                    report.undated_quotes.push((old_id.unwrap_or(i as u32 + 1), date));
+                }
+                added_at
+            }
+            None => None,
+        };
+        quotes.push(QuoteRecord {
+            id: old_id,
+            quote: field(Some(text)).unwrap_or_default(),
+            author: field(author),
+            category: field(category),
+            added_by: None,
+            added_at,
+            deleted_at: None,
+            deleted_by: None,
+        });
+    }
+    Ok(quotes)
+}
+
+// Unix time for a Streamlabs date: `MM/DD/YYYY` as it usually exports them, `DD.MM.YYYY` in some
+// locales, either with an optional `H:MM[:SS] [AM|PM]`, or anything SQLite reads itself
+fn parse_date(db: &Connection, date: &str) -> rusqlite::Result<Option<i64>> {
+    let iso = local_date(date).unwrap_or_else(|| date.to_string());
+    db.query_row("SELECT CAST(strftime('%s', ?1) AS INTEGER)", &[&iso], |row| row.get(0))
+}
+
+// `date` as `YYYY-MM-DD HH:MM:SS`, if it's one of the formats above that SQLite doesn't read
+fn local_date(date: &str) -> Option<String> {
+    let mut words = date.split_whitespace();
+    let day = words.next()?;
+    let parts: Vec<u32> = day
+        .split(|c| c == '/' || c == '.')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    if parts.len() != 3 || parts[2] < 1000 {
+        return None;
+    }
+    let (month, day, year) = if day.contains('/') {
+        (parts[0], parts[1], parts[2])
+    } else {
+        (parts[1], parts[0], parts[2])
+    };
+
+    let (mut hour, minute, second) = match words.next() {
+        Some(time) => {
+            let mut parts = time.split(':');
+            let hour = parts.next()?.parse::<u32>().ok()?;
+            let minute = parts.next()?.parse::<u32>().ok()?;
+            let second = parts.next().map_or(Some(0), |s| s.parse::<u32>().ok())?;
+            (hour, minute, second)
+        }
+        None => (0, 0, 0),
+    };
+    match words.next().map(str::to_uppercase).as_ref().map(String::as_str) {
+        Some("AM") if hour == 12 => hour = 0,
+        Some("PM") if hour < 12 => hour += 12,
+        Some("AM") | Some("PM") | None => {}
+        Some(_) => return None,
+    }
+    Some(format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second))
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Csv(csv::Error),
+    Sqlite(rusqlite::Error),
+    Unsupported(&'static str),
+}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for TransferError {
+    fn from(e: serde_json::Error) -> Self {
+        TransferError::Json(e)
+    }
+}
+
+impl From<csv::Error> for TransferError {
+    fn from(e: csv::Error) -> Self {
+        TransferError::Csv(e)
+    }
+}
+
+impl From<rusqlite::Error> for TransferError {
+    fn from(e: rusqlite::Error) -> Self {
+        TransferError::Sqlite(e)
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransferError::Io(e) => write!(f, "{}", e),
+            TransferError::Json(e) => write!(f, "invalid JSON: {}", e),
+            TransferError::Csv(e) => write!(f, "invalid CSV: {}", e),
+            TransferError::Sqlite(e) => write!(f, "database error: {}", e),
+            TransferError::Unsupported(msg) => write!(f, "{}", msg),
+        }
+    }
+}
@@ -1,131 +1,478 @@
 
 
 use auth::Permissions;
+use backoff::Backoff;
 use cmd;
-use config::Channel;
+use config::{Channel, Owner};
 use irc::client::prelude::*;
 use irc::error::IrcError;
 use irc::proto::message::Tag;
-use rb::*;
-use rusqlite::Connection;
-use state::ThreadState;
+use queue::{Priority, RateBudget, SendQueue};
+use schema;
+use state::{ChatLine, ThreadState};
+use tags::Tags;
+use users;
 use std;
 use std::default::Default;
 use std::io::Write;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+// How long the parting messages get to go out on shutdown
+const DRAIN_TIMEOUT: u64 = 10;
+
+// How a `/me` line starts, as a CTCP ACTION. It ends with another \x01.
+const ACTION: &str = "\u{1}ACTION ";
+
+// Err if the channel can't be opened, which restarting won't fix
 pub fn init(
     state: &Arc<Mutex<ThreadState>>,
     chan_cfg: &Channel,
-    owners: &[String],
+    owners: &[Owner],
     bot_user: &str,
     bot_pass: &str,
-) {
+    controls: &Controls,
+) -> Result<(), String> {
+    let handlers = vec![ChannelHandler::open(state, chan_cfg)?];
+    run(handlers, owners, bot_user, bot_pass, controls);
+    Ok(())
+}
 
-    // Set up IRC config
-    let cfg = Config {
-        owners: Some(owners.to_owned()),
-        nickname: Some(bot_user.to_string()),
-        password: Some(bot_pass.to_string()),
-        server: Some(String::from("irc.chat.twitch.tv")),
-        port: Some(443),
-        use_ssl: Some(true),
-        channels: Some(vec![format!("#{}", chan_cfg.name.to_lowercase())]),
-        ..Default::default()
-    };
+// Join every channel over a single connection. Each channel still gets its own commands,
+// database, log and send budget. Channels that can't be opened are left out, so one bad database
+// doesn't keep the rest from running.
+pub fn init_shared(
+    channels: &[(Arc<Mutex<ThreadState>>, Channel)],
+    owners: &[Owner],
+    bot_user: &str,
+    bot_pass: &str,
+    controls: &Controls,
+) -> Result<(), String> {
+    let handlers: Vec<ChannelHandler> = channels
+        .iter()
+        .filter_map(|(state, chan_cfg)| open_or_notify(state, chan_cfg))
+        .collect();
+    if handlers.is_empty() && !channels.is_empty() {
+        return Err(String::from("no channel could be opened"));
+    }
+    run(handlers, owners, bot_user, bot_pass, controls);
+    Ok(())
+}
 
-    // Open log file
-    let mut log_path = chan_cfg.dir.clone();
-    log_path.push("log");
-    let log_file = std::fs::OpenOptions::new().create(true).append(true).open(
-        log_path,
-    );
-    let mut log = match log_file {
-        Ok(f) => f,
-        Err(e) => panic!("Error: {}", e),
-    };
+// Open a channel, or tell the owners why it's being left out
+fn open_or_notify(state: &Arc<Mutex<ThreadState>>, chan_cfg: &Channel) -> Option<ChannelHandler> {
+    match ChannelHandler::open(state, chan_cfg) {
+        Ok(handler) => Some(handler),
+        Err(e) => {
+            let notice = format!("Leaving out #{}: {}", chan_cfg.name, e);
+            println!("{}", notice);
+            state.lock().unwrap().main.lock().unwrap().notices.push(notice);
+            None
+        }
+    }
+}
 
-    // Open SQLite connection
-    let mut db_path = chan_cfg.dir.clone();
-    db_path.push("db");
-    let db = Connection::open(db_path).unwrap();
-
-    // Try to create tables
-    let _ = db.execute(
-        "CREATE TABLE quote (
-                        id       INTEGER PRIMARY KEY,
-                        quote    TEXT NOT NULL
-                        )",
-        &[],
-    );
-
-    let _ = db.execute(
-        "CREATE TABLE alias (
-                        id         INTEGER PRIMARY KEY,
-                        auth       INTEGER NOT NULL,
-                        alias      TEXT NOT NULL,
-                        command    TEXT NOT NULL
-                        )",
-        &[],
-    );
-
-    {
-        // Add db to ThreadState
-        let mut state = state.lock().unwrap();
-        state.db = Some(db);
-    }
-
-    // Create command buffer
-    let mut cmd_list = cmd::CmdList::new();
-    let mut send_buffer: SpscRb<Option<Instant>> = SpscRb::new(100);
+// Changes to the channels of a running thread, e.g. after the config is reloaded
+pub enum Control {
+    Join(Arc<Mutex<ThreadState>>, Channel),
+    Part(String),
+    Update(Channel),
+    // Part every channel and return
+    Shutdown,
+}
+
+pub type Controls = Arc<Mutex<Vec<Control>>>;
+
+type Queues = Arc<Mutex<Vec<(String, Arc<Mutex<SendQueue>>)>>>;
+
+fn run(
+    mut handlers: Vec<ChannelHandler>,
+    owners: &[Owner],
+    bot_user: &str,
+    bot_pass: &str,
+    controls: &Controls,
+) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(300));
 
     loop {
-        // Start loop to handle twitch RECONNECTs
-        let s = IrcClient::from_config(cfg.clone()).unwrap();
-        let s = match s.identify() {
-            Ok(_) => s,
-            Err(e) => panic!("Unable to identify to server: {}", e),
+        // Asked to stop while disconnected, so there's nobody to say goodbye to
+        if shutdown_requested(controls) {
+            for handler in &mut handlers {
+                handler.close();
+            }
+            return;
+        }
+
+        // Set up IRC config
+        let cfg = Config {
+            owners: Some(owners.iter().map(|owner| owner.name().to_string()).collect()),
+            nickname: Some(bot_user.to_string()),
+            password: Some(bot_pass.to_string()),
+            server: Some(String::from("irc.chat.twitch.tv")),
+            port: Some(443),
+            use_ssl: Some(true),
+            channels: Some(handlers.iter().map(|h| h.irc_chan.clone()).collect()),
+            ..Default::default()
         };
 
-        // Set up extra twitch irc capabilities
-        s.send("CAP REQ :twitch.tv/membership").unwrap();
-        s.send("CAP REQ :twitch.tv/tags").unwrap();
-        s.send("CAP REQ :twitch.tv/commands").unwrap();
+        // Start loop to handle twitch RECONNECTs and dropped connections
+        let s = match connect(&cfg) {
+            Ok(s) => s,
+            Err(e) => {
+                reconnect_wait(&mut backoff, &mut handlers, controls, &format!("unable to connect: {}", e));
+                continue;
+            }
+        };
+
+        // A fresh connection gets a fresh chance to talk, even if we were banned on the last one
+        for handler in &handlers {
+            handler.queue.lock().unwrap().resume();
+        }
+
+        // Drain the outbound queues for as long as this connection lasts
+        let queues = handlers.iter().map(|h| (h.irc_chan.clone(), Arc::clone(&h.queue))).collect();
+        let queues = Arc::new(Mutex::new(queues));
+        let sender = spawn_sender(&s, &queues, controls);
 
         // Main command processing loop
-        s.for_each_incoming(|msg| {
-            // Clone ref to state
-            let state = Arc::clone(&state);
+        let mut done = false;
+        let result = s.for_each_incoming(|msg| {
+            if done {
+                return;
+            }
+
+            // Apply changes from the supervisor. If we're shutting down or there are no channels
+            // left, we're done.
+            let pending: Vec<Control> = controls.lock().unwrap().drain(..).collect();
+            for control in pending {
+                match control {
+                    Control::Shutdown => done = true,
+                    control => apply_control(&s, &mut handlers, &queues, control),
+                }
+            }
+            if done {
+                say_goodbye(&mut handlers);
+            }
+            if done || handlers.is_empty() {
+                let _ = s.send_quit("");
+                done = true;
+                return;
+            }
+
+            // Pass on anything the supervisor wants the owners to know
+            let notices: Vec<String> = {
+                let state = handlers[0].state.lock().unwrap();
+                let mut main = state.main.lock().unwrap();
+                main.notices.drain(..).collect()
+            };
+            for notice in notices {
+                let msgv = owners.iter().map(|owner| format!("/w {} {}", owner.name(), notice)).collect();
+                send_msg(&handlers[0].queue, &handlers[0].irc_chan, Priority::Normal, msgv);
+            }
+
+            // Hand channel messages to their channel, and handle the rest here
+            let target = msg_channel(&msg.command).map(|chan| chan.to_string());
+            if let Some(chan) = target {
+                if let Some(handler) = handlers.iter_mut().find(|h| h.irc_chan == chan) {
+                    handler.handle(msg, owners);
+                }
+                return;
+            }
 
             // Log the message
             let log_msg = log_format(&msg.to_string());
             print!("{}", log_msg);
-            let _ = log.write_all(log_msg.as_bytes());
-
-            // Parse
-            let Message {
-                command,
-                tags,
-                prefix,
-            } = msg;
-            match command {
-                Command::PING(_, None) => s.send("PONG :tmi.twitch.tv").unwrap(),
-                Command::PRIVMSG(chan, mut cmd) => {
-                    if cmd.remove(0) == chan_cfg.cmd_prefix {
-                        let context = Context::new(&chan_cfg.name, tags, prefix, &owners);
-                        let msgv = cmd_list.exec(state, &context, &cmd);
-                        send_msg(&s, &mut send_buffer, &chan, msgv);
-                    }
+            for handler in handlers.iter_mut() {
+                let _ = handler.log.write_all(log_msg.as_bytes());
+            }
+
+            match msg.command {
+                Command::PING(_, None) => {
+                    let _ = s.send("PONG :tmi.twitch.tv");
                 }
-                Command::Raw(cmd, ..) => {
-                    if cmd == "RECONNECT" {
-                        let _ = s.send_quit("");
-                    }
+                Command::Response(Response::RPL_WELCOME, ..) => backoff.reset(),
+                Command::Raw(ref cmd, ..) if cmd == "RECONNECT" => {
+                    let _ = s.send_quit("");
                 }
                 _ => {}
             };
-        }).unwrap();
+        });
+
+        drop(sender);
+
+        // Shut down, or every channel has been parted
+        if done || handlers.is_empty() {
+            return;
+        }
+
+        let reason = match result {
+            Ok(()) => String::from("connection closed"),
+            Err(e) => format!("connection lost: {}", e),
+        };
+        reconnect_wait(&mut backoff, &mut handlers, controls, &reason);
+    }
+}
+
+fn apply_control(s: &IrcClient, handlers: &mut Vec<ChannelHandler>, queues: &Queues, control: Control) {
+    match control {
+        Control::Join(state, chan_cfg) => {
+            let handler = match open_or_notify(&state, &chan_cfg) {
+                Some(handler) => handler,
+                None => return,
+            };
+            queues.lock().unwrap().push((handler.irc_chan.clone(), Arc::clone(&handler.queue)));
+            let _ = s.send_join(&handler.irc_chan);
+            handlers.push(handler);
+        }
+        Control::Part(name) => {
+            let irc_chan = format!("#{}", name.to_lowercase());
+            queues.lock().unwrap().retain(|(chan, _)| *chan != irc_chan);
+            for handler in handlers.iter_mut().filter(|h| h.irc_chan == irc_chan) {
+                handler.close();
+            }
+            handlers.retain(|h| h.irc_chan != irc_chan);
+            let _ = s.send_part(&irc_chan);
+        }
+        Control::Update(chan_cfg) => {
+            let irc_chan = format!("#{}", chan_cfg.name.to_lowercase());
+            if let Some(handler) = handlers.iter_mut().find(|h| h.irc_chan == irc_chan) {
+                handler.update(chan_cfg);
+            }
+        }
+        Control::Shutdown => {}
+    }
+}
+
+fn shutdown_requested(controls: &Controls) -> bool {
+    controls.lock().unwrap().iter().any(|c| matches!(c, Control::Shutdown))
+}
+
+// Send each channel's parting message, give the queues a chance to empty, and close up
+fn say_goodbye(handlers: &mut [ChannelHandler]) {
+    for handler in handlers.iter() {
+        if let Some(msg) = &handler.cfg.part_message {
+            send_msg(&handler.queue, &handler.irc_chan, Priority::High, vec![msg.clone()]);
+        }
+    }
+
+    // The sender thread is still running, so just wait for it
+    let deadline = Instant::now() + Duration::from_secs(DRAIN_TIMEOUT);
+    while Instant::now() < deadline && handlers.iter().any(|h| !h.queue.lock().unwrap().is_drained()) {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    for handler in handlers.iter_mut() {
+        handler.close();
+    }
+}
+
+// Everything belonging to one joined channel
+struct ChannelHandler {
+    cfg: Channel,
+    irc_chan: String,
+    state: Arc<Mutex<ThreadState>>,
+    cmd_list: cmd::CmdList,
+    queue: Arc<Mutex<SendQueue>>,
+    log: std::fs::File,
+}
+
+impl ChannelHandler {
+    // Err with why not, e.g. a database from a newer build
+    fn open(state: &Arc<Mutex<ThreadState>>, chan_cfg: &Channel) -> Result<Self, String> {
+        // Open log file
+        let mut log_path = chan_cfg.log_dir.clone();
+        log_path.push("log");
+        let log_file = std::fs::OpenOptions::new().create(true).append(true).open(
+            &log_path,
+        );
+        let log = match log_file {
+            Ok(f) => f,
+            Err(e) => return Err(format!("{}: {}", log_path.display(), e)),
+        };
+
+        // Open SQLite connection and bring its schema up to date
+        let db_path = chan_cfg.db_path();
+        let db = match schema::open(&db_path) {
+            Ok(db) => db,
+            Err(e) => return Err(format!("{}: {}", db_path.display(), e)),
+        };
+
+        {
+            // Add db and settings to ThreadState
+            let mut state = state.lock().unwrap();
+            state.db = Some(db);
+            state.cfg = chan_cfg.clone();
+        }
+
+        // Create command buffer
+        let cmd_list = cmd::CmdList::new(chan_cfg);
+        let queue = SendQueue::new(chan_cfg.queue_size, chan_cfg.queue_overflow);
+
+        Ok(Self {
+            cfg: chan_cfg.clone(),
+            irc_chan: format!("#{}", chan_cfg.name.to_lowercase()),
+            state: Arc::clone(state),
+            cmd_list,
+            queue: Arc::new(Mutex::new(queue)),
+            log,
+        })
+    }
+
+    // Close the database and make sure the log is written out
+    fn close(&mut self) {
+        let log_msg = log_format(&format!("CLOSE {}\n", self.irc_chan));
+        print!("{}", log_msg);
+        let _ = self.log.write_all(log_msg.as_bytes());
+        let _ = self.log.flush();
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(db) = state.db.take() {
+            if let Err((_, e)) = db.close() {
+                println!("Unable to close database for {}: {}", self.irc_chan, e);
+            }
+        }
+    }
+
+    // Apply new settings without reconnecting
+    fn update(&mut self, chan_cfg: Channel) {
+        self.cmd_list.configure(&chan_cfg);
+        self.queue.lock().unwrap().configure(chan_cfg.queue_size, chan_cfg.queue_overflow);
+        self.state.lock().unwrap().cfg = chan_cfg.clone();
+        self.cfg = chan_cfg;
+    }
+
+    // Keep the database's login for the sender's user-id current, before anything looks them up
+    fn record_user(&self, context: &Context) {
+        let id = match &context.tags.user_id {
+            Some(id) => id,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.known_users.get(id) == Some(&context.sender) {
+            return;
+        }
+        if let Some(db) = &state.db {
+            let display_name = context.tags.display_name.as_ref().map(String::as_str);
+            if let Err(e) = users::seen(db, id, &context.sender, display_name) {
+                println!("Unable to record user {} ({}): {}", context.sender, id, e);
+                return;
+            }
+        }
+        state.known_users.insert(id.clone(), context.sender.clone());
+    }
+
+    fn handle(&mut self, msg: Message, owners: &[Owner]) {
+        // Clone ref to state
+        let state = Arc::clone(&self.state);
+
+        // Log the message
+        let log_msg = log_format(&msg.to_string());
+        print!("{}", log_msg);
+        let _ = self.log.write_all(log_msg.as_bytes());
+
+        // Parse
+        let Message {
+            command,
+            tags,
+            prefix,
+        } = msg;
+        match command {
+            Command::PRIVMSG(chan, text) => {
+                let context = Context::new(&self.cfg.name, tags, prefix, owners);
+                self.record_user(&context);
+                if text.starts_with(self.cfg.cmd_prefix) {
+                    let cmd = &text[self.cfg.cmd_prefix.len_utf8()..];
+                    if let Some((priority, msgv)) = self.cmd_list.exec(state, &context, cmd) {
+                        send_msg(&self.queue, &chan, priority, msgv);
+                    }
+                } else {
+                    // Remember what was said, for !grab, without the wrapping of a `/me` line
+                    let text = if text.starts_with(ACTION) {
+                        text[ACTION.len()..].trim_end_matches('\u{1}').to_string()
+                    } else {
+                        text
+                    };
+                    let line = ChatLine {
+                        display_name: context.get_sender_display().unwrap_or_else(|| context.sender.clone()),
+                        user: context.sender,
+                        text,
+                        time: unix_time(),
+                    };
+                    state.lock().unwrap().recent.push(line);
+                }
+            }
+            Command::NOTICE(chan, text) => {
+                if let Some(notice) = Notice::parse(&tags, &text) {
+                    handle_notice(&self.queue, &mut self.log, &chan, &notice);
+                }
+            }
+            Command::Raw(cmd, ..) => {
+                if cmd == "USERSTATE" || cmd == "ROOMSTATE" {
+                    // Our standing in the room decides how fast we may talk
+                    let mut state = state.lock().unwrap();
+                    if cmd == "USERSTATE" {
+                        state.bot.update(&tags);
+                    } else {
+                        state.room.update(&tags);
+                    }
+                    let budget = state.room.budget(&state.bot);
+                    self.queue.lock().unwrap().set_budget(budget);
+                }
+            }
+            _ => {}
+        };
+    }
+}
+
+// The channel a message was sent to, if any
+fn msg_channel(command: &Command) -> Option<&str> {
+    let chan = match command {
+        Command::PRIVMSG(chan, _) | Command::NOTICE(chan, _) => chan,
+        Command::JOIN(chan, ..) | Command::PART(chan, _) => chan,
+        // USERSTATE, ROOMSTATE, CLEARCHAT, USERNOTICE...
+        Command::Raw(_, args, _) => args.first()?,
+        _ => return None,
+    };
+    if chan.starts_with('#') {
+        Some(chan)
+    } else {
+        None
+    }
+}
+
+fn connect(cfg: &Config) -> std::result::Result<IrcClient, IrcError> {
+    let s = IrcClient::from_config(cfg.clone())?;
+    s.identify()?;
+
+    // Set up extra twitch irc capabilities
+    s.send("CAP REQ :twitch.tv/membership")?;
+    s.send("CAP REQ :twitch.tv/tags")?;
+    s.send("CAP REQ :twitch.tv/commands")?;
+    Ok(s)
+}
+
+// Log why the connection went away, then sleep until it's time for the next attempt
+fn reconnect_wait(backoff: &mut Backoff, handlers: &mut [ChannelHandler], controls: &Controls, reason: &str) {
+    let delay = backoff.next_delay();
+    let chans: Vec<&str> = handlers.iter().map(|h| h.irc_chan.as_str()).collect();
+    let log_msg = log_format(&format!(
+        "RECONNECT {} (attempt {}, waiting {}ms): {}\n",
+        chans.join(","),
+        backoff.attempt(),
+        delay.as_secs() * 1000 + u64::from(delay.subsec_millis()),
+        reason
+    ));
+    print!("{}", log_msg);
+    for handler in handlers.iter_mut() {
+        let _ = handler.log.write_all(log_msg.as_bytes());
+    }
+
+    // Don't keep a shutdown waiting
+    let until = Instant::now() + delay;
+    while Instant::now() < until && !shutdown_requested(controls) {
+        thread::sleep(Duration::from_millis(100));
     }
 }
 
@@ -133,7 +480,7 @@ pub struct Context {
     pub sender: String,
     pub channel: String,
     pub auth: Permissions,
-    pub tags: Option<Vec<Tag>>,
+    pub tags: Tags,
     pub prefix: Option<String>,
 }
 
@@ -142,9 +489,10 @@ impl Context {
         channel: &str,
         tags: Option<Vec<Tag>>,
         prefix: Option<String>,
-        owners: &[String],
+        owners: &[Owner],
     ) -> Self {
         let sender = Self::user_from_prefix(&prefix);
+        let tags = Tags::parse(&tags);
         let auth = Self::eval_auth(&tags, &sender, owners);
         Self {
             sender,
@@ -155,20 +503,17 @@ impl Context {
         }
     }
 
+    pub fn user_id(&self) -> Option<&str> {
+        self.tags.user_id.as_ref().map(String::as_str)
+    }
+
+    // What to key per-user data on. The login is only a fallback, since it can change hands.
+    pub fn user_key(&self) -> &str {
+        self.user_id().unwrap_or(&self.sender)
+    }
+
     pub fn get_sender_display(&self) -> Option<String> {
-        if let Some(tags) = &self.tags {
-            for Tag(key, val) in tags {
-                if key == "display-name" {
-                    if let Some(val) = val {
-                        let display_name = val.clone();
-                        return Some(display_name);
-                    }
-                }
-            }
-            None
-        } else {
-            None
-        }
+        self.tags.display_name.clone()
     }
 
     fn user_from_prefix(prefix: &Option<String>) -> String {
@@ -177,45 +522,198 @@ impl Context {
         String::from(prefix[0])
     }
 
-    fn eval_auth(tags: &Option<Vec<Tag>>, sender: &str, owners: &[String]) -> Permissions {
+    fn eval_auth(tags: &Tags, sender: &str, owners: &[Owner]) -> Permissions {
         let mut perms = Permissions::Viewer;
-        if let Some(tags) = tags {
-            // Check if user is an owner
-            for owner in owners {
-                if sender == owner.as_str() {
-                    perms.set(Permissions::Owner, true);
-                    break;
-                }
-            }
 
-            // Otherwise get their auth from tags
-            for Tag(key, val) in tags {
-                if key == "badges" {
-                    if let Some(val) = val {
-                        if val.contains("broadcaster") {
-                            perms.set(Permissions::Streamer, true);
-                        }
-                        if val.contains("moderator") {
-                            perms.set(Permissions::Mod, true);
-                        }
-                        if val.contains("subscriber") {
-                            perms.set(Permissions::Sub, true);
-                        }
-                    }
-                    break;
-                }
-            }
+        // Check if user is an owner
+        let user_id = tags.user_id.as_ref().map(String::as_str);
+        if owners.iter().any(|owner| owner.is(user_id, sender)) {
+            perms.set(Permissions::Owner, true);
+        }
+
+        // Otherwise get their auth from their badges
+        perms.set(Permissions::Streamer, tags.has_badge("broadcaster"));
+        perms.set(Permissions::Mod, tags.has_badge("moderator"));
+        perms.set(Permissions::Sub, tags.has_badge("subscriber"));
+        perms.set(Permissions::Vip, tags.has_badge("vip"));
+        perms.set(Permissions::Artist, tags.has_badge("artist-badge"));
+        perms.set(Permissions::Staff, ["staff", "admin", "global_mod"].iter().any(|b| tags.has_badge(b)));
 
+        // The founder badge replaces the subscriber one, but they're still a sub
+        if tags.has_badge("founder") {
+            perms.set(Permissions::Founder, true);
+            perms.set(Permissions::Sub, true);
         }
         perms
     }
 }
 
+// A NOTICE from Twitch, typed by its `msg-id` tag
+#[derive(Debug, PartialEq)]
+pub enum Notice {
+    RateLimited,
+    Duplicate,
+    Banned,
+    TimedOut(Option<Duration>),
+    Other(String),
+}
+
+impl Notice {
+    pub fn parse(tags: &Option<Vec<Tag>>, text: &str) -> Option<Notice> {
+        let notice = match get_tag(tags, "msg-id")? {
+            "msg_ratelimit" => Notice::RateLimited,
+            "msg_duplicate" => Notice::Duplicate,
+            "msg_banned" => Notice::Banned,
+            // "You are timed out for 593 more seconds."
+            "msg_timedout" => Notice::TimedOut(
+                text.split_whitespace()
+                    .find_map(|word| word.parse::<u64>().ok())
+                    .map(Duration::from_secs),
+            ),
+            id => Notice::Other(id.to_string()),
+        };
+        Some(notice)
+    }
+
+    // Whether Twitch refused to deliver a message we sent. Every such notice has a `msg_` id,
+    // the rest are informational (e.g. `slow_on`).
+    pub fn is_rejection(&self) -> bool {
+        match self {
+            Notice::Other(id) => id.starts_with("msg_"),
+            _ => true,
+        }
+    }
+}
+
+fn handle_notice(queue: &Mutex<SendQueue>, log: &mut std::fs::File, chan: &str, notice: &Notice) {
+    if !notice.is_rejection() {
+        return;
+    }
+    let mut queue = queue.lock().unwrap();
+
+    let failed = queue.last_sent().unwrap_or("").to_string();
+    let log_msg = log_format(&format!("REJECTED ({:?}) >>> PRIVMSG {} :{}\n", notice, chan, failed));
+    print!("{}", log_msg);
+    let _ = log.write_all(log_msg.as_bytes());
+
+    match notice {
+        Notice::Duplicate => {
+            if queue.retry_last(true).is_none() {
+                println!("Giving up on duplicate message to {}\n", chan);
+            }
+        }
+        Notice::RateLimited => {
+            // Whatever we think our budget is, Twitch disagrees. Let the window pass.
+            queue.retry_last(false);
+            queue.hold(Duration::from_secs(30));
+        }
+        Notice::Banned => queue.suspend(),
+        Notice::TimedOut(duration) => {
+            // Anything queued would be stale by the time the timeout ends
+            queue.clear();
+            queue.hold(duration.unwrap_or_else(|| Duration::from_secs(600)));
+        }
+        Notice::Other(_) => {}
+    }
+}
+
+// Chat settings of a channel, from ROOMSTATE
+#[derive(Clone, Debug, Default)]
+pub struct RoomState {
+    pub emote_only: bool,
+    // Minutes a user must have followed for; `None` when followers-only mode is off
+    pub followers_only: Option<u32>,
+    pub r9k: bool,
+    // Seconds between messages; 0 when slow mode is off
+    pub slow: u32,
+    pub subs_only: bool,
+}
+
+impl RoomState {
+    // ROOMSTATE only carries the settings that changed, so anything missing is left alone
+    pub fn update(&mut self, tags: &Option<Vec<Tag>>) {
+        if let Some(val) = get_tag(tags, "emote-only") {
+            self.emote_only = val == "1";
+        }
+        if let Some(val) = get_tag(tags, "followers-only") {
+            self.followers_only = val.parse::<i32>().ok().filter(|&m| m >= 0).map(|m| m as u32);
+        }
+        if let Some(val) = get_tag(tags, "r9k") {
+            self.r9k = val == "1";
+        }
+        if let Some(val) = get_tag(tags, "slow") {
+            self.slow = val.parse().unwrap_or(0);
+        }
+        if let Some(val) = get_tag(tags, "subs-only") {
+            self.subs_only = val == "1";
+        }
+    }
+
+    // How fast the bot may talk here, or `None` if the room's settings keep it from talking at
+    // all. Followers-only mode isn't considered, since the bot's account is expected to follow
+    // the channels it's in.
+    pub fn budget(&self, bot: &UserState) -> Option<RateBudget> {
+        if bot.is_privileged() {
+            return Some(RateBudget::new(100, 30));
+        }
+        if self.emote_only || (self.subs_only && !bot.subscriber) {
+            return None;
+        }
+        if self.slow > 0 {
+            Some(RateBudget::new(1, u64::from(self.slow)))
+        } else {
+            Some(RateBudget::new(20, 30))
+        }
+    }
+}
+
+// The bot's own badges in a channel, from USERSTATE
+#[derive(Clone, Debug, Default)]
+pub struct UserState {
+    pub broadcaster: bool,
+    pub moderator: bool,
+    pub vip: bool,
+    pub subscriber: bool,
+}
+
+impl UserState {
+    pub fn update(&mut self, tags: &Option<Vec<Tag>>) {
+        let badges = get_tag(tags, "badges").unwrap_or("");
+        let has_badge = |name| badges.split(',').any(|b| b.split('/').next() == Some(name));
+        self.broadcaster = has_badge("broadcaster");
+        self.moderator = has_badge("moderator") || get_tag(tags, "mod") == Some("1");
+        self.vip = has_badge("vip");
+        self.subscriber = has_badge("subscriber") || has_badge("founder");
+    }
+
+    // Broadcasters, mods and VIPs get the higher rate limit and ignore chat restrictions
+    pub fn is_privileged(&self) -> bool {
+        self.broadcaster || self.moderator || self.vip
+    }
+}
+
+fn get_tag<'a>(tags: &'a Option<Vec<Tag>>, key: &str) -> Option<&'a str> {
+    if let Some(tags) = tags {
+        for Tag(k, val) in tags {
+            if k == key {
+                return val.as_ref().map(|v| v.as_str());
+            }
+        }
+    }
+    None
+}
+
 fn chanmsg(s: &IrcClient, chan: &str, msg: &str) -> std::result::Result<(), IrcError> {
     println!("SENDING >>> PRIVMSG {un} :{}\n", msg, un = chan);
     s.send_privmsg(chan, msg)
 }
 
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
 fn log_format(s: &str) -> String {
     use std::time::SystemTime;
     if let Ok(time) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
@@ -225,45 +723,67 @@ fn log_format(s: &str) -> String {
     }
 }
 
-fn send_msg(
-    s: &IrcClient,
-    send_buffer: &mut SpscRb<Option<Instant>>,
-    chan: &str,
-    msgv: Option<Vec<String>>,
-) {
-    if let Some(msgv) = msgv {
-        let (prod, cons) = (send_buffer.producer(), send_buffer.consumer());
-
-        let mut purge_count = 0;
-        let mut buffer = [None; 100];
-        if cons.get(&mut buffer).is_ok() {
-            for inst in buffer.into_iter() {
-                if let Some(inst) = inst {
-                    if inst.elapsed().as_secs() >= 30 {
-                        purge_count += 1;
-                    } else {
-                        break;
-                    }
-                } else {
-                    break;
-                }
-            }
+// Queue a command's response for the channel's sender thread
+fn send_msg(queue: &Mutex<SendQueue>, chan: &str, priority: Priority, msgv: Vec<String>) {
+    let mut queue = queue.lock().unwrap();
+    for msg in msgv {
+        if let Some(dropped) = queue.push(Priority::of(&msg, priority), msg) {
+            println!("DROPPED >>> PRIVMSG {} :{}\n", chan, dropped);
         }
-        if purge_count == 100 {
-            cons.skip_pending().unwrap();
-        } else if purge_count > 0 {
-            cons.skip(purge_count).unwrap();
+    }
+}
+
+// The thread sending for one connection. It's stopped when this is dropped, including when a
+// handler panics, so a restarted channel never ends up with two senders.
+struct SendThread {
+    connected: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for SendThread {
+    fn drop(&mut self) {
+        self.connected.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
         }
+    }
+}
+
+fn spawn_sender(s: &IrcClient, queues: &Queues, controls: &Controls) -> SendThread {
+    let connected = Arc::new(AtomicBool::new(true));
+    let s = s.clone();
+    let queues = Arc::clone(queues);
+    let controls = Arc::clone(controls);
+    let running = Arc::clone(&connected);
+    let handle = thread::spawn(move || {
+        let mut woken = false;
+        while running.load(Ordering::SeqCst) {
+            // Controls are applied as messages come in, so make one come in
+            let pending = !controls.lock().unwrap().is_empty();
+            if pending && !woken {
+                let _ = s.send(Command::PING(String::from("tmi.twitch.tv"), None));
+            }
+            woken = pending;
 
-        if send_buffer.slots_free() >= msgv.len() {
-            let mut instv = Vec::new();
-            for _ in &msgv {
-                instv.push(Some(Instant::now()));
+            // Each channel's queue keeps to its own budget
+            let mut idle = true;
+            let queues = queues.lock().unwrap().clone();
+            for (chan, queue) in &queues {
+                let msg = queue.lock().unwrap().pop();
+                if let Some(msg) = msg {
+                    if chanmsg(&s, chan, &msg).is_err() {
+                        return;
+                    }
+                    idle = false;
+                }
             }
-            prod.write(&instv).unwrap();
-            for msg in msgv {
-                chanmsg(s, chan, &msg).unwrap();
+            if idle {
+                thread::sleep(Duration::from_millis(50));
             }
         }
+    });
+    SendThread {
+        connected,
+        handle: Some(handle),
     }
 }
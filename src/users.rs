@@ -0,0 +1,39 @@
+use rusqlite::{self, Connection};
+
+// Logins change and are given out again once abandoned, user-ids don't. Every user seen in chat is
+// recorded so that names typed in commands can be turned into ids.
+
+// Record that `id` goes by `login`. Role grants made by name before their id was known are pinned
+// to it now, and grants that are already pinned follow them if they've been renamed.
+pub fn seen(db: &Connection, id: &str, login: &str, display_name: Option<&str>) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT OR IGNORE INTO twitch_user (id, login, display_name, first_seen, updated_at)
+         VALUES (?1, ?2, ?3, strftime('%s', 'now'), strftime('%s', 'now'))",
+        &[&id, &login, &display_name],
+    )?;
+    db.execute(
+        "UPDATE twitch_user SET login=?2, display_name=?3, updated_at=strftime('%s', 'now') WHERE id=?1",
+        &[&id, &login, &display_name],
+    )?;
+    // A grant by name for a role they already have by id is one too many
+    db.execute(
+        "DELETE FROM role_member WHERE user_id IS NULL AND login=?2
+         AND role_id IN (SELECT role_id FROM role_member WHERE user_id=?1)",
+        &[&id, &login],
+    )?;
+    db.execute(
+        "UPDATE role_member SET user_id=?1, login=?2 WHERE user_id=?1 OR (user_id IS NULL AND login=?2)",
+        &[&id, &login],
+    )?;
+    Ok(())
+}
+
+// Whoever last went by `login`
+pub fn id_of(db: &Connection, login: &str) -> rusqlite::Result<Option<String>> {
+    let mut stmt = db.prepare("SELECT id FROM twitch_user WHERE login=?1 ORDER BY updated_at DESC LIMIT 1")?;
+    let mut rows = stmt.query(&[&login])?;
+    match rows.next() {
+        Some(row) => Ok(Some(row?.get(0))),
+        None => Ok(None),
+    }
+}